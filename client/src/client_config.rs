@@ -1,13 +1,292 @@
+use crate::filter::Action;
 use config::{File, FileFormat};
+use cross_messages::quic::Quic;
+use cross_messages::transport::{TransportConfig, TransportKind};
+use crossconfig::Layers;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+/// Name of the config file in the user config directory.
+pub const FILE_NAME: &str = "crosslive_client.toml";
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ClientConfig {
+    /// Not needed in LAN mode
+    #[serde(default)]
     pub master_addr: String,
+    #[serde(default)]
     pub master_port: u16,
+    /// Further Masters as `host:port`, failed over to when the active one is lost
+    #[serde(default)]
+    pub fallback_masters: Vec<String>,
+    #[serde(default)]
+    pub master_order: MasterOrder,
+    /// Has to match the transport of the Master
+    #[serde(default)]
+    pub transport: TransportKind,
+    pub log_level: Option<String>,
+    #[serde(default = "default_group")]
+    pub group: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub device_name: Option<String>,
+    pub token: Option<String>,
+    /// Verified on connect over QUIC, elsewhere only compared with the one the Master reports
+    pub master_fingerprint: Option<String>,
+    /// Syncs with clients of the same group on the LAN instead of a Master.
+    pub lan: Option<LanConfig>,
+    /// Sends large clipboards straight to other devices when they can be reached.
+    pub direct: Option<DirectConfig>,
+    /// Keeps copied and received clipboards, see `client history`.
+    pub history: Option<HistoryConfig>,
+    /// Pushes copied clipboards to the history the Master keeps for the group.
+    pub shared_history: Option<SharedHistoryConfig>,
+    /// Keeps secrets from being synced, on by default.
+    #[serde(default)]
+    pub filters: FilterConfig,
+    /// Limits what is synced with whom, everything by default.
+    #[serde(default)]
+    pub sync: SyncConfig,
+    /// Unix socket for `client push`, `client pull` and `client history apply`
+    pub control_socket: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct SyncConfig {
+    #[serde(default)]
+    pub mode: SyncMode,
+    /// Copies are only sent by `client push`, pulls of other devices are refused
+    #[serde(default)]
+    pub manual: bool,
+    /// If any are listed, only these devices are synced with, by name.
+    /// Devices report their names themselves, so these only keep honest
+    /// devices apart, they don't stop one posing as another.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Devices never synced with, by name, see `allow`
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Larger copies are not sent
+    pub max_send_bytes: Option<usize>,
+    /// Larger clipboards of other devices are ignored
+    pub max_receive_bytes: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+    #[default]
+    Both,
+    SendOnly,
+    ReceiveOnly,
+}
+
+impl SyncConfig {
+    pub fn sends(&self) -> bool {
+        self.mode != SyncMode::ReceiveOnly
+    }
+
+    pub fn receives(&self) -> bool {
+        self.mode != SyncMode::SendOnly
+    }
+
+    pub fn over_send_limit(&self, len: usize) -> bool {
+        self.max_send_bytes.is_some_and(|max| len > max)
+    }
+
+    /// Whether copies may go to the shared history, which every device of
+    /// the group can read regardless of `allow` and `deny`.
+    pub fn shares_history(&self) -> bool {
+        self.sends() && !self.manual && !self.needs_names()
+    }
+
+    /// Whether peers have to be known by name for `allows`.
+    pub fn needs_names(&self) -> bool {
+        !self.allow.is_empty() || !self.deny.is_empty()
+    }
+
+    /// `name` is `None` while the peer's name isn't known, which is only
+    /// synced with if nothing is listed.
+    pub fn allows(&self, name: Option<&str>) -> bool {
+        if !self.needs_names() {
+            return true;
+        }
+        let Some(name) = name else {
+            return false;
+        };
+
+        (self.allow.is_empty() || self.allow.iter().any(|allowed| allowed == name))
+            && !self.deny.iter().any(|denied| denied == name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct LanConfig {
+    /// Where this client is announced, as `ip:port`.
+    #[serde(default = "default_discovery_addr")]
+    pub discovery_addr: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct DirectConfig {
+    /// Messages with fewer bytes are still relayed by the Master.
+    #[serde(default = "default_min_bytes")]
+    pub min_bytes: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct HistoryConfig {
+    /// Defaults to `crosslive_history.db` in the user config directory
+    pub database: Option<String>,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    /// Entries older than this are removed
+    pub max_age_days: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct SharedHistoryConfig {
+    /// Encrypts entries so only devices with the same password can read them
+    pub password: Option<String>,
+}
+
+/// Actions for the built-in filters, and rules of their own.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct FilterConfig {
+    /// Copies marked by a password manager
+    #[serde(default = "default_block")]
+    pub manager_hints: Action,
+    /// Tokens of well-known services, e.g. AWS, GitHub or Stripe
+    #[serde(default = "default_redact")]
+    pub api_keys: Action,
+    /// PEM and PGP private keys
+    #[serde(default = "default_block")]
+    pub private_keys: Action,
+    #[serde(default)]
+    pub rules: Vec<FilterRule>,
+    /// How long other devices keep ephemeral copies on their clipboard
+    #[serde(default = "default_ephemeral_ttl")]
+    pub ephemeral_ttl_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct FilterRule {
+    /// Shown in the logs instead of the pattern
+    pub name: Option<String>,
+    /// Regex, redacting replaces every match
+    pub pattern: String,
+    pub action: Action,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            manager_hints: default_block(),
+            api_keys: default_redact(),
+            private_keys: default_block(),
+            rules: Vec::new(),
+            ephemeral_ttl_secs: default_ephemeral_ttl(),
+        }
+    }
+}
+
+fn default_ephemeral_ttl() -> u64 {
+    60
+}
+
+fn default_block() -> Action {
+    Action::Block
+}
+
+fn default_redact() -> Action {
+    Action::Redact
+}
+
+impl HistoryConfig {
+    pub fn path(&self) -> Option<std::path::PathBuf> {
+        match &self.database {
+            Some(path) => Some(path.into()),
+            None => crossconfig::default_path(crate::history::FILE_NAME),
+        }
+    }
+}
+
+fn default_max_entries() -> usize {
+    1000
+}
+
+fn default_min_bytes() -> usize {
+    client_lib::direct::DEFAULT_MIN_BYTES
+}
+
+fn default_discovery_addr() -> String {
+    format!(
+        "255.255.255.255:{}",
+        client_lib::lan::DEFAULT_DISCOVERY_PORT
+    )
+}
+
+/// Which Master is tried first.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MasterOrder {
+    /// `master_addr` first, then the fallbacks as listed.
+    #[default]
+    InOrder,
+    /// The one accepting a connection fastest first.
+    Latency,
+}
+
+fn default_group() -> String {
+    cross_messages::DEFAULT_GROUP.to_string()
 }
 
 impl ClientConfig {
+    /// A config for the Master at `addr`, given as `host:port`.
+    pub fn new(addr: &str) -> anyhow::Result<Self> {
+        let mut config = ClientConfig {
+            master_addr: String::new(),
+            master_port: 0,
+            fallback_masters: Vec::new(),
+            master_order: MasterOrder::default(),
+            transport: TransportKind::default(),
+            log_level: None,
+            group: default_group(),
+            user: None,
+            password: None,
+            device_name: None,
+            token: None,
+            master_fingerprint: None,
+            lan: None,
+            direct: None,
+            history: None,
+            shared_history: None,
+            filters: FilterConfig::default(),
+            sync: SyncConfig::default(),
+            control_socket: None,
+        };
+
+        config.set_master(addr)?;
+        Ok(config)
+    }
+
+    pub fn set_master(&mut self, addr: &str) -> anyhow::Result<()> {
+        let (host, port) = addr
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Expected 'host:port', got '{}'", addr))?;
+
+        self.master_addr = host.to_string();
+        self.master_port = port.parse()?;
+        Ok(())
+    }
+
+    /// Loads the merged config and fails on any problem found by `check`.
+    pub fn load(layers: &Layers) -> anyhow::Result<Self> {
+        let config: ClientConfig = layers.load()?;
+        crossconfig::validate(config.check())?;
+        Ok(config)
+    }
+
+    /// Reads only the file at `path`, e.g. to update and save it again.
     pub fn get_from(path: &str) -> Result<Self, config::ConfigError> {
         config::Config::builder()
             .add_source(File::new(path, FileFormat::Toml))
@@ -15,24 +294,227 @@ impl ClientConfig {
             .try_deserialize()
     }
 
+    pub fn control_socket_path(&self) -> std::path::PathBuf {
+        match &self.control_socket {
+            Some(path) => path.into(),
+            None => match dirs::runtime_dir() {
+                Some(dir) => dir.join("crosslive_client.sock"),
+                None => std::env::temp_dir().join(crate::control::shared_socket_name()),
+            },
+        }
+    }
+
+    /// The transport of the Master, expecting its fingerprint over QUIC.
+    pub fn transport_config(&self) -> TransportConfig {
+        TransportConfig {
+            kind: self.transport,
+            quic: Quic::pinned(self.master_fingerprint.clone()),
+        }
+    }
+
     pub fn master_addr(&self) -> String {
         format!("{}:{}", self.master_addr, self.master_port)
     }
 
-    pub fn get() -> Result<Self, config::ConfigError> {
-        if let Ok(v) = std::env::var("CROSSCONFIG") {
-            return Self::get_from(&v);
+    /// The primary Master followed by the fallbacks, as `host:port`.
+    pub fn masters(&self) -> Vec<String> {
+        let mut masters = vec![self.master_addr()];
+        masters.extend(self.fallback_masters.iter().cloned());
+        masters
+    }
+
+    /// Problems which would keep the client from connecting as configured.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        match &self.lan {
+            Some(lan) => {
+                if lan.discovery_addr.parse::<std::net::SocketAddr>().is_err() {
+                    problems.push(format!(
+                        "lan.discovery_addr '{}' is not 'ip:port'",
+                        lan.discovery_addr
+                    ));
+                }
+            }
+            None => {
+                if self.master_addr.trim().is_empty() {
+                    problems.push("master_addr is empty".to_string());
+                }
+                if self.master_port == 0 {
+                    problems.push("master_port must be greater than 0".to_string());
+                }
+            }
         }
+        for master in &self.fallback_masters {
+            let valid = matches!(
+                master.rsplit_once(':'),
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok_and(|p| p > 0)
+            );
+            if !valid {
+                problems.push(format!("fallback master '{}' is not 'host:port'", master));
+            }
+        }
+        if self
+            .direct
+            .as_ref()
+            .is_some_and(|direct| direct.min_bytes == 0)
+        {
+            problems.push("direct.min_bytes must be greater than 0".to_string());
+        }
+        if self
+            .history
+            .as_ref()
+            .is_some_and(|history| history.max_entries == 0)
+        {
+            problems.push("history.max_entries must be greater than 0".to_string());
+        }
+        if self
+            .shared_history
+            .as_ref()
+            .is_some_and(|shared| shared.password.as_ref().is_some_and(String::is_empty))
+        {
+            problems.push("shared_history.password is empty".to_string());
+        }
+        if let Err(e) = crate::filter::Filters::new(&self.filters) {
+            problems.push(format!("filters: {}", e));
+        }
+        if self.filters.ephemeral_ttl_secs == 0 {
+            problems.push("filters.ephemeral_ttl_secs must be greater than 0".to_string());
+        }
+        if self.sync.max_send_bytes == Some(0) || self.sync.max_receive_bytes == Some(0) {
+            problems.push(
+                "sync.max_send_bytes and sync.max_receive_bytes must be greater than 0".to_string(),
+            );
+        }
+        if self.group.trim().is_empty() {
+            problems.push("group is empty".to_string());
+        }
+
+        if let Some(level) = &self.log_level {
+            if crosslogging::parse_level(level).is_none() {
+                problems.push(format!("log_level '{}' is invalid", level));
+            }
+        }
+
+        if self.user.is_none() && (self.password.is_some() || self.token.is_some()) {
+            problems.push("password and token require a user".to_string());
+        }
+        if self.token.is_some() && self.device_name.is_none() {
+            problems.push("token requires a device_name".to_string());
+        }
+
+        problems
+    }
+
+    /// Settings which differ from `other` and only apply after a restart.
+    pub fn restart_required(&self, other: &ClientConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.master_addr() != other.master_addr() {
+            changed.push("master_addr/master_port");
+        }
+        if self.transport != other.transport {
+            changed.push("transport");
+        }
+        if self.user != other.user || self.password != other.password || self.token != other.token {
+            changed.push("credentials");
+        }
+        if self.device_name != other.device_name {
+            changed.push("device_name");
+        }
+        if self.master_fingerprint != other.master_fingerprint {
+            changed.push("master_fingerprint");
+        }
+        if self.lan != other.lan {
+            changed.push("lan");
+        }
+        if self.direct != other.direct {
+            changed.push("direct");
+        }
+        if self.history != other.history {
+            changed.push("history");
+        }
+        if self.shared_history != other.shared_history {
+            changed.push("shared_history");
+        }
+        if self.control_socket != other.control_socket {
+            changed.push("control_socket");
+        }
+        changed
+    }
+
+    /// The key shared history entries are sealed with, if a password is set.
+    pub fn history_key(&self) -> anyhow::Result<Option<client_lib::e2e::HistoryKey>> {
+        match self
+            .shared_history
+            .as_ref()
+            .and_then(|s| s.password.as_ref())
+        {
+            Some(password) => Ok(Some(client_lib::e2e::HistoryKey::derive(
+                password,
+                &self.group,
+            )?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn lan_config(&self) -> Option<client_lib::lan::LanConfig> {
+        let lan = self.lan.as_ref()?;
+        let mut config = client_lib::lan::LanConfig::new(&self.group);
+        config.device = self.device_name.clone();
+        config.discovery_addr = lan.discovery_addr.parse().ok()?;
+        Some(config)
+    }
+
+    pub fn register_request(&self) -> cross_messages::RegisterRequest {
+        cross_messages::RegisterRequest {
+            group: self.group.clone(),
+            user: self.user.clone(),
+            password: self.password.clone(),
+            // Accounts revoke per device, so it's always named
+            device: Some(
+                self.device_name
+                    .clone()
+                    .unwrap_or_else(crate::pairing::hostname),
+            ),
+            token: self.token.clone(),
+        }
+    }
+
+    /// Takes over the credentials and group handed out by pairing.
+    pub fn apply_grant(&mut self, grant: cross_messages::PairingGrant) {
+        self.group = grant.group;
+        self.user = grant.user;
+        self.device_name = Some(grant.device);
+        self.token = grant.token;
+        self.password = None;
+        self.master_fingerprint = grant.fingerprint;
+    }
+
+    pub fn save(&self, path: &str) -> anyhow::Result<()> {
+        if let Some(parent) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        write_private(path, toml::to_string_pretty(self)?.as_bytes())?;
+        Ok(())
+    }
+}
 
-        let path = format!(
-            "{}/crosslive_client.toml",
-            dirs::config_local_dir()
-                .unwrap()
-                .as_path()
-                .to_str()
-                .unwrap()
-        );
+/// The config holds the pairing token, so only the owner may read it.
+fn write_private(path: &str, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
 
-        Self::get_from(&path)
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let mut file = options.open(path)?;
+        // Created before it was private
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        file.write_all(contents)
     }
+    #[cfg(not(unix))]
+    options.open(path)?.write_all(contents)
 }
@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::sync::{mpsc, oneshot};
+
+/// A command sent over the control socket, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    /// Sends `content`, or the clipboard if none, to the device named `to`
+    /// or to every peer the sync rules allow.
+    Push {
+        content: Option<String>,
+        to: Option<String>,
+    },
+    /// Asks the device named `device` for its clipboard.
+    Pull { device: String },
+    /// Puts `content` on the clipboard, which the running client owns.
+    Apply { content: String },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlReply {
+    Pushed { devices: Vec<String> },
+    Pulled { bytes: usize },
+    Applied { bytes: usize },
+    Error { message: String },
+}
+
+impl ControlReply {
+    pub fn error(message: impl std::fmt::Display) -> Self {
+        ControlReply::Error {
+            message: message.to_string(),
+        }
+    }
+}
+
+/// A command for the running client and where to send its reply.
+pub type Command = (ControlRequest, oneshot::Sender<ControlReply>);
+
+#[cfg(unix)]
+pub use unix::*;
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{UnixListener, UnixStream};
+
+    /// Name of the socket in a directory shared by all users.
+    pub fn shared_socket_name() -> String {
+        // SAFETY: getuid always succeeds
+        format!("crosslive_client-{}.sock", unsafe { libc::getuid() })
+    }
+
+    /// Refuses sockets of other users, who would get what is pushed or pulled.
+    fn check_owner(path: &Path) -> anyhow::Result<()> {
+        let owner = std::fs::symlink_metadata(path)?.uid();
+        // SAFETY: getuid always succeeds
+        anyhow::ensure!(
+            owner == unsafe { libc::getuid() },
+            "{} belongs to another user",
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Accepts commands on the Unix socket at `path` and passes them to `tx`.
+    /// The socket is only accessible by the user running the client.
+    pub async fn serve(path: &Path, tx: mpsc::Sender<Command>) -> anyhow::Result<()> {
+        if path.exists() {
+            check_owner(path)?;
+            if UnixStream::connect(path).await.is_ok() {
+                anyhow::bail!("{} is used by another running client", path.display());
+            }
+            std::fs::remove_file(path)?;
+        }
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let listener = bind_private(path)?;
+        log::info!("Listening for control commands on {}", path.display());
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_connection(stream, tx).await {
+                    log::warn!("Control connection failed due to {}", e);
+                }
+            });
+        }
+    }
+
+    /// Binds the socket in a directory only the current user can enter and
+    /// moves it to `path` once it's 0600, so it's never reachable by others.
+    fn bind_private(path: &Path) -> anyhow::Result<UnixListener> {
+        let dir = path.with_extension(format!("{}.tmp", std::process::id()));
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+        let bound = dir.join("socket");
+        let res = UnixListener::bind(&bound)
+            .and_then(|listener| {
+                std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(0o600))?;
+                std::fs::rename(&bound, path)?;
+                Ok(listener)
+            })
+            .map_err(Into::into);
+
+        let _ = std::fs::remove_dir_all(&dir);
+        res
+    }
+
+    async fn serve_connection(stream: UnixStream, tx: mpsc::Sender<Command>) -> anyhow::Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            let reply = match serde_json::from_str::<ControlRequest>(&line) {
+                Ok(req) => {
+                    log::debug!("Control command {:?}", req);
+                    let (reply_tx, reply_rx) = oneshot::channel();
+                    tx.send((req, reply_tx)).await?;
+                    reply_rx
+                        .await
+                        .unwrap_or_else(|_| ControlReply::error("The client is shutting down"))
+                }
+                Err(e) => ControlReply::error(format!("Invalid command: {}", e)),
+            };
+
+            let mut reply = serde_json::to_string(&reply)?;
+            reply.push('\n');
+            write.write_all(reply.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends a single command to the client listening on `path`.
+    pub async fn request(path: &Path, req: &ControlRequest) -> anyhow::Result<ControlReply> {
+        if path.exists() {
+            check_owner(path)?;
+        }
+        let stream = UnixStream::connect(path).await.map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to connect to {}, is the client running? ({})",
+                path.display(),
+                e
+            )
+        })?;
+        let (read, mut write) = stream.into_split();
+
+        let mut line = serde_json::to_string(req)?;
+        line.push('\n');
+        write.write_all(line.as_bytes()).await?;
+
+        match BufReader::new(read).lines().next_line().await? {
+            Some(line) => Ok(serde_json::from_str(&line)?),
+            None => anyhow::bail!("The client closed the control connection"),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn shared_socket_name() -> String {
+    "crosslive_client.sock".to_string()
+}
+
+#[cfg(not(unix))]
+pub async fn serve(_path: &Path, _tx: mpsc::Sender<Command>) -> anyhow::Result<()> {
+    anyhow::bail!("The control socket is only supported on Unix")
+}
+
+#[cfg(not(unix))]
+pub async fn request(_path: &Path, _req: &ControlRequest) -> anyhow::Result<ControlReply> {
+    anyhow::bail!("The control socket is only supported on Unix")
+}
@@ -9,6 +9,10 @@ impl AsyncClipboard for copypasta::ClipboardContext {
         Ok(ClipboardContext::new().unwrap())
     }
 
+    async fn get(&mut self) -> anyhow::Result<String> {
+        self.get_contents().map_err(|e| anyhow::anyhow!(e))
+    }
+
     async fn get_new(&mut self) -> anyhow::Result<String> {
         let start_content = self.get_contents().unwrap();
         loop {
@@ -26,4 +30,21 @@ impl AsyncClipboard for copypasta::ClipboardContext {
         self.set_contents(new).unwrap();
         Ok(())
     }
+
+    async fn offers(&mut self, format: &str) -> anyhow::Result<bool> {
+        // copypasta keeps its connection to itself
+        let clipboard = x11_clipboard::Clipboard::new()?;
+        let atoms = &clipboard.getter.atoms;
+        let targets = clipboard.load(
+            atoms.clipboard,
+            atoms.targets,
+            atoms.property,
+            Duration::from_secs(3),
+        )?;
+        let format = clipboard.getter.get_atom(format)?;
+
+        Ok(targets
+            .chunks_exact(4)
+            .any(|atom| u32::from_ne_bytes([atom[0], atom[1], atom[2], atom[3]]) == format))
+    }
 }
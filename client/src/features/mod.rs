@@ -3,8 +3,6 @@ pub mod linux_clipboard;
 #[cfg(target_os = "windows")]
 pub mod win_clipboard;
 
-#[cfg(target_os = "linux")]
-pub use linux_clipboard as clipboard;
 #[cfg(target_os = "windows")]
 pub use win_clipboard as clipboard;
 
@@ -14,6 +12,11 @@ pub trait AsyncClipboard {
     where
         Self: Sized;
 
+    /// The current content, without waiting for a change.
+    async fn get(&mut self) -> anyhow::Result<String>;
     async fn get_new(&mut self) -> anyhow::Result<String>;
     async fn set(&mut self, _: String) -> anyhow::Result<()>;
+
+    /// Whether the current content is also offered as `format`, e.g. a MIME type.
+    async fn offers(&mut self, format: &str) -> anyhow::Result<bool>;
 }
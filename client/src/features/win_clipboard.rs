@@ -11,6 +11,10 @@ impl AsyncClipboard for WindowsClipboardWrapper {
         Ok(WindowsClipboardWrapper)
     }
 
+    async fn get(&mut self) -> anyhow::Result<String> {
+        clipboard_win::get_clipboard(formats::Unicode).map_err(|e| anyhow::anyhow!("{}", e))
+    }
+
     async fn get_new(&mut self) -> anyhow::Result<String> {
         let old: String = clipboard_win::get_clipboard(formats::Unicode).unwrap_or_else(|_| {
             clipboard_win::set_clipboard(formats::Unicode, "Init WinClip").unwrap();
@@ -31,4 +35,9 @@ impl AsyncClipboard for WindowsClipboardWrapper {
         clipboard_win::set_clipboard(formats::Unicode, new).unwrap();
         Ok(())
     }
+
+    async fn offers(&mut self, format: &str) -> anyhow::Result<bool> {
+        Ok(clipboard_win::register_format(format)
+            .is_some_and(|format| clipboard_win::is_format_avail(format.get())))
+    }
 }
\ No newline at end of file
@@ -0,0 +1,395 @@
+use crate::client_config::{ClientConfig, HistoryConfig};
+use crate::control;
+use client_lib::e2e::HistoryKey;
+use client_lib::{CrossClient, CrossHandle};
+use cross_messages::{HistoryEntry, HistoryQuery, MessageKind, ID};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the database in the user config directory.
+pub const FILE_NAME: &str = "crosslive_history.db";
+
+/// Content type of everything synced so far.
+pub const TEXT: &str = "text/plain";
+
+#[derive(clap::Subcommand)]
+pub enum HistoryCommand {
+    /// List the latest entries
+    List {
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+        /// From the history the Master keeps for the group
+        #[arg(long)]
+        shared: bool,
+        /// Only entries copied on this device
+        #[arg(long, requires = "shared")]
+        device: Option<String>,
+    },
+    /// Print an entry in full
+    Show {
+        id: i64,
+        #[arg(long)]
+        shared: bool,
+    },
+    /// Put an entry back on the clipboard through the running client
+    Apply {
+        id: i64,
+        #[arg(long)]
+        shared: bool,
+    },
+    /// List entries containing all of the words
+    Search {
+        #[arg(required = true)]
+        words: Vec<String>,
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+}
+
+pub struct Entry {
+    pub id: i64,
+    /// Seconds since the Unix epoch.
+    pub created: i64,
+    /// The device it was copied on.
+    pub origin: String,
+    pub content_type: String,
+    pub content: String,
+}
+
+/// Clipboards copied here or received from other devices, kept in sqlite
+/// with a full-text index.
+pub struct History {
+    conn: Connection,
+    max_entries: usize,
+    max_age: Option<Duration>,
+}
+
+impl History {
+    pub fn open(config: &HistoryConfig) -> anyhow::Result<Self> {
+        let path = config
+            .path()
+            .ok_or_else(|| anyhow::anyhow!("No path for the history database"))?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        create_private(&path)?;
+
+        let conn = Connection::open(&path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS entries (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                created INTEGER NOT NULL,
+                origin TEXT NOT NULL,
+                content_type TEXT NOT NULL,
+                content TEXT NOT NULL
+            );
+            CREATE VIRTUAL TABLE IF NOT EXISTS entries_fts
+                USING fts5(content, content='entries', content_rowid='id');
+            CREATE TRIGGER IF NOT EXISTS entries_insert AFTER INSERT ON entries BEGIN
+                INSERT INTO entries_fts (rowid, content) VALUES (new.id, new.content);
+            END;
+            CREATE TRIGGER IF NOT EXISTS entries_delete AFTER DELETE ON entries BEGIN
+                INSERT INTO entries_fts (entries_fts, rowid, content)
+                    VALUES ('delete', old.id, old.content);
+            END;",
+        )?;
+
+        Ok(History {
+            conn,
+            max_entries: config.max_entries,
+            max_age: config
+                .max_age_days
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+        })
+    }
+
+    /// Adds an entry and drops those past the retention. Copying the same
+    /// again only moves the latest entry to now.
+    pub fn record(&self, origin: &str, content_type: &str, content: &str) -> anyhow::Result<()> {
+        let now = unix_now();
+        let latest: Option<(i64, String, String)> = self
+            .conn
+            .query_row(
+                "SELECT id, origin, content FROM entries ORDER BY id DESC LIMIT 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+
+        match latest {
+            Some((id, latest_origin, latest_content))
+                if latest_origin == origin && latest_content == content =>
+            {
+                self.conn.execute(
+                    "UPDATE entries SET created = ?1 WHERE id = ?2",
+                    params![now, id],
+                )?;
+            }
+            _ => {
+                self.conn.execute(
+                    "INSERT INTO entries (created, origin, content_type, content)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![now, origin, content_type, content],
+                )?;
+            }
+        }
+
+        self.prune(now)
+    }
+
+    fn prune(&self, now: i64) -> anyhow::Result<()> {
+        if let Some(max_age) = self.max_age {
+            self.conn.execute(
+                "DELETE FROM entries WHERE created < ?1",
+                params![now - max_age.as_secs() as i64],
+            )?;
+        }
+        self.conn.execute(
+            "DELETE FROM entries WHERE id NOT IN
+                (SELECT id FROM entries ORDER BY id DESC LIMIT ?1)",
+            params![self.max_entries as i64],
+        )?;
+        Ok(())
+    }
+
+    /// The latest entries, newest first.
+    pub fn list(&self, limit: usize) -> anyhow::Result<Vec<Entry>> {
+        self.query(
+            "SELECT id, created, origin, content_type, content FROM entries
+             ORDER BY id DESC LIMIT ?1",
+            params![limit as i64],
+        )
+    }
+
+    pub fn get(&self, id: i64) -> anyhow::Result<Option<Entry>> {
+        Ok(self
+            .query(
+                "SELECT id, created, origin, content_type, content FROM entries WHERE id = ?1",
+                params![id],
+            )?
+            .pop())
+    }
+
+    /// Entries containing all `words`, best matches first.
+    pub fn search(&self, words: &[String], limit: usize) -> anyhow::Result<Vec<Entry>> {
+        // Quoted, so words are never taken as FTS5 syntax
+        let query = words
+            .iter()
+            .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        self.query(
+            "SELECT e.id, e.created, e.origin, e.content_type, e.content
+             FROM entries_fts JOIN entries e ON e.id = entries_fts.rowid
+             WHERE entries_fts MATCH ?1 ORDER BY rank LIMIT ?2",
+            params![query, limit as i64],
+        )
+    }
+
+    fn query(&self, sql: &str, params: impl rusqlite::Params) -> anyhow::Result<Vec<Entry>> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let entries = stmt
+            .query_map(params, |row| {
+                Ok(Entry {
+                    id: row.get(0)?,
+                    created: row.get(1)?,
+                    origin: row.get(2)?,
+                    content_type: row.get(3)?,
+                    content: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+}
+
+/// Runs `client history ...`.
+pub async fn run(layers: &crossconfig::Layers, command: HistoryCommand) -> anyhow::Result<()> {
+    let config = ClientConfig::load(layers)?;
+    if let HistoryCommand::List { shared: true, .. }
+    | HistoryCommand::Show { shared: true, .. }
+    | HistoryCommand::Apply { shared: true, .. } = command
+    {
+        return run_shared(&config, command).await;
+    }
+
+    let history = config.history.as_ref().ok_or_else(|| {
+        anyhow::anyhow!("History is disabled, add a [history] table to the config")
+    })?;
+    let history = History::open(history)?;
+
+    match command {
+        HistoryCommand::List { limit, .. } => print_entries(&history.list(limit)?),
+        HistoryCommand::Search { words, limit } => print_entries(&history.search(&words, limit)?),
+        HistoryCommand::Show { id, .. } => {
+            let entry = history
+                .get(id)?
+                .ok_or_else(|| anyhow::anyhow!("No history entry {}", id))?;
+            print_entry(&entry);
+        }
+        HistoryCommand::Apply { id, .. } => {
+            let entry = history
+                .get(id)?
+                .ok_or_else(|| anyhow::anyhow!("No history entry {}", id))?;
+            apply(&config, entry).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs a command against the history of the Master, as this device.
+async fn run_shared(config: &ClientConfig, command: HistoryCommand) -> anyhow::Result<()> {
+    let key = config.history_key()?;
+    let (mut client, mut handle) =
+        CrossClient::connect(&config.transport_config(), &config.master_addr())
+            .await?
+            .pin_fingerprint(config.master_fingerprint.clone())
+            .register(config.register_request())
+            .await?;
+    let client_handle = tokio::spawn(async move { client.run().await });
+
+    let res = request_shared(config, &mut handle, key.as_ref(), command).await;
+
+    handle
+        .send(ID::Master, MessageKind::Close, String::new())
+        .await?;
+    client_handle.await??;
+    res
+}
+
+async fn request_shared(
+    config: &ClientConfig,
+    handle: &mut CrossHandle,
+    key: Option<&HistoryKey>,
+    command: HistoryCommand,
+) -> anyhow::Result<()> {
+    match command {
+        HistoryCommand::List { limit, device, .. } => {
+            let entries = handle.history(&HistoryQuery { device, limit }).await?;
+            // Listed even if they can't be read, only show and apply need the content
+            let entries = entries
+                .into_iter()
+                .map(|mut entry| {
+                    if entry.encrypted {
+                        entry.content = key
+                            .and_then(|key| key.open(&entry.content_type, &entry.content).ok())
+                            .unwrap_or_else(|| "(encrypted)".to_string());
+                        entry.encrypted = false;
+                    }
+                    shared_entry(entry, key)
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            print_entries(&entries);
+        }
+        HistoryCommand::Show { id, .. } => {
+            print_entry(&shared_entry(handle.history_entry(id).await?, key)?)
+        }
+        HistoryCommand::Apply { id, .. } => {
+            apply(config, shared_entry(handle.history_entry(id).await?, key)?).await?
+        }
+        HistoryCommand::Search { .. } => {
+            return Err(anyhow::anyhow!("Only the local history can be searched"))
+        }
+    }
+    Ok(())
+}
+
+/// Decrypts the entry if needed.
+fn shared_entry(entry: HistoryEntry, key: Option<&HistoryKey>) -> anyhow::Result<Entry> {
+    let content = match (entry.encrypted, key) {
+        (false, _) => entry.content,
+        (true, Some(key)) => key.open(&entry.content_type, &entry.content)?,
+        (true, None) => {
+            return Err(anyhow::anyhow!(
+                "Entry {} is encrypted, set shared_history.password",
+                entry.id
+            ))
+        }
+    };
+
+    Ok(Entry {
+        id: entry.id,
+        created: entry.created,
+        origin: entry.device,
+        content_type: entry.content_type,
+        content,
+    })
+}
+
+fn print_entry(entry: &Entry) {
+    println!(
+        "{} from {} ({})\n\n{}",
+        format_time(entry.created),
+        entry.origin,
+        entry.content_type,
+        entry.content
+    );
+}
+
+/// Left to the running client, the clipboard may only hold content while
+/// its owner lives, e.g. on X11.
+async fn apply(config: &ClientConfig, entry: Entry) -> anyhow::Result<()> {
+    let req = control::ControlRequest::Apply {
+        content: entry.content,
+    };
+    match control::request(&config.control_socket_path(), &req).await? {
+        control::ControlReply::Applied { .. } => println!("Applied entry {}", entry.id),
+        reply => return crate::unexpected(reply),
+    }
+    Ok(())
+}
+
+/// Creates the database only readable by the user, sqlite keeps the
+/// permissions for its journal.
+fn create_private(path: &std::path::Path) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(false);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        options.open(path)?;
+        // Created before it was private
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+    }
+    #[cfg(not(unix))]
+    options.open(path).map(|_| ())
+}
+
+fn print_entries(entries: &[Entry]) {
+    for entry in entries {
+        let preview: String = entry
+            .content
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .chars()
+            .take(60)
+            .collect();
+        println!(
+            "{:>6}  {}  {:<20}  {}",
+            entry.id,
+            format_time(entry.created),
+            entry.origin,
+            preview
+        );
+    }
+}
+
+fn format_time(secs: i64) -> String {
+    match chrono::DateTime::from_timestamp(secs, 0) {
+        Some(time) => time
+            .with_timezone(&chrono::Local)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+        None => secs.to_string(),
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
@@ -1,5 +1,9 @@
 mod client_config;
+mod control;
 mod features;
+mod filter;
+mod history;
+mod pairing;
 
 use client_lib::*;
 use cross_messages::*;
@@ -9,42 +13,382 @@ use features::AsyncClipboard;
 #[cfg(target_os = "windows")]
 use features::clipboard;
 
+#[derive(clap::Parser)]
+#[command(about = "Crosslive clipboard sync client")]
+struct Cli {
+    /// Config file, defaults to `CROSSCONFIG` or the user config directory
+    #[arg(long, global = true)]
+    config: Option<String>,
+    /// Group to sync with, overrides the config
+    #[arg(long, global = true)]
+    group: Option<String>,
+    /// One of error, warn, info, debug or trace
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+    /// Overrides any config value, e.g. `--set device_name=laptop`
+    #[arg(
+        long = "set",
+        value_name = "KEY=VALUE",
+        value_parser = crossconfig::parse_override,
+        global = true
+    )]
+    overrides: Vec<(String, String)>,
+    /// Print the config merged from file, `CROSSLIVE_*` env vars and flags
+    #[arg(long, global = true)]
+    print_config: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+impl Cli {
+    fn layers(&self) -> crossconfig::Layers {
+        let mut layers = crossconfig::Layers::new(self.config.clone(), client_config::FILE_NAME);
+        if let Some(group) = &self.group {
+            layers.set("group", group);
+        }
+        if let Some(log_level) = &self.log_level {
+            layers.set("log_level", log_level);
+        }
+        layers.set_all(self.overrides.clone());
+        layers
+    }
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Run the clipboard sync (default)
+    Run,
+    /// Request a pairing code for a new device
+    PairCode,
+    /// Pair this device using a code from an already trusted device
+    Pair {
+        code: String,
+        /// Master address as `host:port`, defaults to the one in the config
+        #[arg(long)]
+        master: Option<String>,
+        /// Name of this device, defaults to the hostname
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Send the clipboard once through the running client
+    Push {
+        /// Name of the only device to send to, defaults to all allowed
+        #[arg(long)]
+        to: Option<String>,
+        /// Send stdin instead of the clipboard
+        #[arg(long)]
+        stdin: bool,
+    },
+    /// Apply the clipboard of another device through the running client
+    Pull { device: String },
+    /// List, search and re-apply past clipboards
+    History {
+        #[command(subcommand)]
+        command: history::HistoryCommand,
+    },
+}
+
 #[tokio::main]
 async fn main() {
-    crosslogging::init_fern_logger().unwrap();
+    let cli = <Cli as clap::Parser>::parse();
+    let layers = cli.layers();
 
-    let config = client_config::ClientConfig::get().unwrap();
-    let (mut client, handle) = CrossClient::new(config.master_addr())
-        .await
-        .unwrap_or_else(|e| {
-            log::error!("Failed to connect to Master Server due to '{}'", e);
+    // Before the logger, so stdout only holds the config
+    if cli.print_config {
+        if let Err(e) = print_config(&layers) {
+            eprintln!("Error: {}", e);
             std::process::exit(1);
-        })
-        .register()
-        .await
-        .unwrap_or_else(|e| {
-            log::error!("Failed to register at Master Server due to '{}'", e);
-            std::process::exit(2);
-        });
+        }
+        return;
+    }
+
+    crosslogging::init_fern_logger().unwrap();
+    let res = match cli.command.unwrap_or(Command::Run) {
+        Command::Run => {
+            run(layers).await;
+            Ok(())
+        }
+        Command::PairCode => pairing::pair_code(&layers).await,
+        Command::Pair { code, master, name } => pairing::pair(&layers, code, master, name).await,
+        Command::Push { to, stdin } => push(&layers, to, stdin).await,
+        Command::Pull { device } => pull(&layers, device).await,
+        Command::History { command } => history::run(&layers, command).await,
+    };
 
-    let thread_handle = tokio::spawn(async move { client.run().await.expect("Hello from thread") });
+    if let Err(e) = res {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
+}
 
-    let mut client = Client::new(handle).await.unwrap();
+/// Time given to a peer to answer `client pull`.
+const PULL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
 
-    if let Err(e) = client.start().await {
-        log::error!("Interal Client error '{}'", e);
+async fn push(layers: &crossconfig::Layers, to: Option<String>, stdin: bool) -> anyhow::Result<()> {
+    let config = client_config::ClientConfig::load(layers)?;
+    let content = match stdin {
+        true => Some(std::io::read_to_string(std::io::stdin())?),
+        false => None,
+    };
+
+    let req = control::ControlRequest::Push { content, to };
+    match control::request(&config.control_socket_path(), &req).await? {
+        control::ControlReply::Pushed { devices } if devices.is_empty() => {
+            println!("No device to push to")
+        }
+        control::ControlReply::Pushed { devices } => println!("Pushed to {}", devices.join(", ")),
+        reply => return unexpected(reply),
+    }
+    Ok(())
+}
+
+async fn pull(layers: &crossconfig::Layers, device: String) -> anyhow::Result<()> {
+    let config = client_config::ClientConfig::load(layers)?;
+    let req = control::ControlRequest::Pull {
+        device: device.clone(),
+    };
+
+    let res = tokio::time::timeout(
+        PULL_TIMEOUT,
+        control::request(&config.control_socket_path(), &req),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("No answer from '{}'", device))?;
+    match res? {
+        control::ControlReply::Pulled { bytes } => {
+            println!("Applied {} bytes from '{}'", bytes, device)
+        }
+        reply => return unexpected(reply),
+    }
+    Ok(())
+}
+
+fn unexpected(reply: control::ControlReply) -> anyhow::Result<()> {
+    match reply {
+        control::ControlReply::Error { message } => anyhow::bail!(message),
+        reply => anyhow::bail!("Unexpected reply {:?}", reply),
+    }
+}
+
+fn print_config(layers: &crossconfig::Layers) -> anyhow::Result<()> {
+    let config: client_config::ClientConfig = layers.load()?;
+    print!("{}", crossconfig::to_redacted_toml(&config)?);
+    Ok(())
+}
+
+/// `--log-level` goes over `LOGLEVEL`, which goes over the config.
+fn apply_log_level(
+    layers: &crossconfig::Layers,
+    config: &client_config::ClientConfig,
+) -> anyhow::Result<()> {
+    match layers.get("log_level") {
+        Some(level) => crosslogging::set_level(level),
+        None => crosslogging::set_config_level(config.log_level.as_deref()),
+    }
+}
+
+async fn run(layers: crossconfig::Layers) {
+    let config = client_config::ClientConfig::load(&layers).unwrap_or_else(|e| {
+        log::error!("Failed to load config due to {}", e);
+        std::process::exit(1);
+    });
+    if let Err(e) = apply_log_level(&layers, &config) {
+        log::warn!("Keeping the default log level, {}", e);
+    }
+
+    let res = match config.lan_config() {
+        Some(lan) => start_lan(lan).await,
+        None => connect(&config, None).await,
+    };
+    let (mut active, mut session, handle) = res.unwrap_or_else(|e| {
+        log::error!("Failed to start syncing due to '{}'", e);
+        std::process::exit(1);
+    });
+
+    let mut client = Client::new(handle, config).await.unwrap();
+
+    let (reload_tx, mut reloads) = tokio::sync::mpsc::channel(1);
+    tokio::spawn(watch_config(layers, reload_tx));
+
+    let (control_tx, mut commands) = tokio::sync::mpsc::channel(4);
+    let socket = client.config.control_socket_path();
+    tokio::spawn(async move {
+        if let Err(e) = control::serve(&socket, control_tx).await {
+            log::error!("Control socket failed due to {}", e);
+        }
+    });
+
+    loop {
+        match client.start(&mut reloads, &mut commands).await {
+            Ok(Stop::Quit) => break,
+            Ok(Stop::Lost) => {}
+            Err(e) => {
+                log::error!("Interal Client error '{}'", e);
+                break;
+            }
+        }
+
+        let _ = session.await;
+        let Some(lost) = active else {
+            log::error!("LAN mode stopped");
+            client.clear_ephemeral().await;
+            return;
+        };
+        log::warn!("Lost connection to Master Server {}, failing over", lost);
+
+        let config = client.config.clone();
+        let failover = failover(&config, lost);
+        tokio::pin!(failover);
+        loop {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {
+                    client.clear_ephemeral().await;
+                    return;
+                }
+                // Still due while no Master is reachable
+                _ = until(client.expiring.as_ref().map(|(due, _)| *due)) => {
+                    client.clear_ephemeral().await
+                }
+                (addr, new_session, handle) = &mut failover => {
+                    active = addr;
+                    session = new_session;
+                    if let Err(e) = client.reconnect(handle).await {
+                        log::warn!("Failed to fetch peers from {:?} due to {}", active, e);
+                    }
+                    break;
+                }
+            }
+        }
     }
+    client.clear_ephemeral().await;
 
-    client
-        .handle
-        .send(ID::Master, MessageKind::Close, String::new())
+    // The connection is already gone if the Master shut down
+    if !session.is_finished() {
+        client
+            .handle
+            .send(ID::Master, MessageKind::Close, String::new())
+            .await
+            .unwrap_or_else(|e| {
+                log::error!("Failed to properly Close due to '{}'", e);
+                std::process::exit(3);
+            });
+    }
+
+    session.await.unwrap();
+}
+
+/// The Master connected to, if any, the task running the connection and its handle.
+type Session = (
+    Option<std::net::SocketAddr>,
+    tokio::task::JoinHandle<()>,
+    CrossHandle,
+);
+
+/// Registers at the first Master which accepts, trying `lost` last.
+async fn connect(
+    config: &client_config::ClientConfig,
+    lost: Option<std::net::SocketAddr>,
+) -> anyhow::Result<Session> {
+    let mut addrs = masters::resolve(&config.masters()).await;
+    if config.master_order == client_config::MasterOrder::Latency {
+        addrs = masters::by_latency(addrs, config.transport_config()).await;
+    }
+    if let Some(lost) = lost {
+        addrs.retain(|addr| *addr != lost);
+        addrs.push(lost);
+    }
+
+    let mut last_err = anyhow::anyhow!("No Master address could be resolved");
+    for addr in addrs {
+        log::info!("Connecting to Master Server {}", addr);
+        match tokio::time::timeout(masters::CONNECT_TIMEOUT, register(config, addr)).await {
+            Ok(Ok((mut client, handle))) => {
+                let session = tokio::spawn(async move {
+                    if let Err(e) = client.run().await {
+                        log::warn!("Connection to Master Server failed due to {}", e);
+                    }
+                });
+                return Ok((Some(addr), session, handle));
+            }
+            Ok(Err(e)) => last_err = e,
+            Err(_) => last_err = anyhow::anyhow!("Timed out connecting to {}", addr),
+        }
+        log::warn!("Failed to register at {} due to '{}'", addr, last_err);
+    }
+
+    Err(last_err)
+}
+
+async fn start_lan(lan: client_lib::lan::LanConfig) -> anyhow::Result<Session> {
+    let (mut node, handle) = client_lib::lan::LanNode::start(lan).await?;
+    let session = tokio::spawn(async move {
+        if let Err(e) = node.run().await {
+            log::warn!("LAN mode failed due to {}", e);
+        }
+    });
+    Ok((None, session, handle))
+}
+
+async fn register(
+    config: &client_config::ClientConfig,
+    addr: std::net::SocketAddr,
+) -> anyhow::Result<(RegisteredClient, CrossHandle)> {
+    CrossClient::connect(&config.transport_config(), &addr.to_string())
+        .await?
+        .pin_fingerprint(config.master_fingerprint.clone())
+        .direct_connections(config.direct.as_ref().map(|direct| direct.min_bytes))
+        .register(config.register_request())
         .await
-        .unwrap_or_else(|e| {
-            log::error!("Failed to properly Close due to '{}'", e);
-            std::process::exit(3);
-        });
+}
+
+/// Retries all Masters with a growing delay until one accepts.
+async fn failover(config: &client_config::ClientConfig, lost: std::net::SocketAddr) -> Session {
+    let mut delay = std::time::Duration::from_secs(1);
+    loop {
+        match connect(config, Some(lost)).await {
+            Ok(session) => return session,
+            Err(e) => {
+                log::warn!(
+                    "No Master Server available ({}), retrying in {:?}",
+                    e,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(std::time::Duration::from_secs(60));
+            }
+        }
+    }
+}
+
+/// Sends the config whenever its file changed or SIGHUP was received.
+async fn watch_config(
+    layers: crossconfig::Layers,
+    tx: tokio::sync::mpsc::Sender<client_config::ClientConfig>,
+) {
+    let Some(path) = layers.file() else {
+        log::info!("No config file to watch for changes");
+        return;
+    };
 
-    thread_handle.await.unwrap();
+    let mut watcher = match crossconfig::ConfigWatcher::new(path) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            log::warn!("Failed to watch the config for changes due to {}", e);
+            return;
+        }
+    };
+
+    while watcher.changed().await {
+        log::info!("Reloading config from {}", watcher.path().display());
+        match client_config::ClientConfig::load(&layers) {
+            Ok(config) => {
+                if tx.send(config).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => log::error!("Keeping the current config, {}", e),
+        }
+    }
 }
 
 struct Client<T>
@@ -52,26 +396,79 @@ where
     T: AsyncClipboard,
 {
     handle: CrossHandle,
+    config: client_config::ClientConfig,
     clipboard: T,
     old_clipboard_content: String,
     other_devices: Vec<ID>,
+    history: Option<history::History>,
+    history_key: Option<client_lib::e2e::HistoryKey>,
+    filters: filter::Filters,
+    /// Received content to clear from the clipboard once due, if still there.
+    expiring: Option<(tokio::time::Instant, String)>,
+    /// Names of the other devices, only fetched if sync rules list any.
+    peer_names: std::collections::HashMap<ID, String>,
+    /// The peer asked by `client pull`, until when the pull waits for it
+    /// and where to reply once it answered.
+    pulling: Option<(
+        ID,
+        tokio::time::Instant,
+        tokio::sync::oneshot::Sender<control::ControlReply>,
+    )>,
+}
+
+/// Why `Client::start` returned.
+enum Stop {
+    Quit,
+    /// The connection to the Master is gone.
+    Lost,
 }
 
 impl<T> Client<T>
 where
     T: AsyncClipboard,
 {
-    async fn start(&mut self) -> anyhow::Result<()> {
+    async fn start(
+        &mut self,
+        reloads: &mut tokio::sync::mpsc::Receiver<client_config::ClientConfig>,
+        commands: &mut tokio::sync::mpsc::Receiver<control::Command>,
+    ) -> anyhow::Result<Stop> {
         loop {
             tokio::select! {
                 _ = tokio::signal::ctrl_c() => {
-                    return Ok(())
+                    return Ok(Stop::Quit)
                 },
 
+                Some(config) = reloads.recv() => self.apply_config(config).await,
+
+                Some((req, reply)) = commands.recv() => match req {
+                    control::ControlRequest::Push { content, to } => {
+                        let _ = reply.send(self.push(content, to).await);
+                    }
+                    control::ControlRequest::Pull { device } => self.pull(&device, reply).await,
+                    control::ControlRequest::Apply { content } => {
+                        let bytes = content.len();
+                        let _ = reply.send(match self.clipboard.set(content).await {
+                            Ok(()) => control::ControlReply::Applied { bytes },
+                            Err(e) => control::ControlReply::error(e),
+                        });
+                    }
+                },
+
+                _ = until(self.expiring.as_ref().map(|(due, _)| *due)) => self.clear_ephemeral().await,
+
                 res = self.handle.recv() => {
                     match res {
+                        Some(msg) if msg.header.kind == MessageKind::Shutdown => {
+                            let notice = ShutdownNotice::from_message(&msg).unwrap_or_default();
+                            log::warn!(
+                                "Master is shutting down ({}), reconnect after {}s",
+                                notice.reason.as_deref().unwrap_or("no reason given"),
+                                notice.reconnect_after_secs.unwrap_or_default()
+                            );
+                            return Ok(Stop::Lost);
+                        }
                         Some(msg) => self.handle_message(msg).await?,
-                        None => return Err(anyhow::anyhow!("None from handler.recv()"))
+                        None => return Ok(Stop::Lost)
                     }
                 },
 
@@ -81,24 +478,474 @@ where
                     if s == self.old_clipboard_content {
                         continue;
                     }
+                    self.old_clipboard_content = s.clone();
+
+                    let hinted = self.password_manager_hint().await;
+                    let (s, ttl_secs) = match self.outgoing(&s, hinted) {
+                        Ok(outgoing) => outgoing,
+                        Err(by) => {
+                            log::info!("Not syncing clipboard, blocked by filter '{}'", by);
+                            continue;
+                        }
+                    };
+                    // Only kept on this device, unlike the shared history
+                    if ttl_secs.is_none() {
+                        self.record(&self.local_origin(), &s);
+                    }
+
+                    let sync = &self.config.sync;
+                    if !sync.sends() || sync.manual {
+                        continue;
+                    }
+                    if sync.over_send_limit(s.len()) {
+                        log::info!("Not sending clipboard of {} bytes, over sync.max_send_bytes", s.len());
+                        continue;
+                    }
+                    if ttl_secs.is_none() && sync.shares_history() {
+                        self.push_shared(&s).await;
+                    }
 
                     for other in &self.other_devices {
-                        self.handle.send(other.clone(), MessageKind::Clipboard, s.clone()).await?;
+                        if !sync.allows(self.peer_names.get(other).map(String::as_str)) {
+                            log::debug!("Not sending clipboard to {:?}, not allowed by sync rules", other);
+                            continue;
+                        }
+                        if self.handle.send_with_ttl(other.clone(), MessageKind::Clipboard, s.clone(), ttl_secs).await.is_err() {
+                            return Ok(Stop::Lost);
+                        }
                     }
                 }
             }
         }
     }
 
+    /// Continues on a new connection, e.g. to another Master.
+    async fn reconnect(&mut self, handle: CrossHandle) -> anyhow::Result<()> {
+        self.handle = handle;
+        self.other_devices = fetch_devices(&mut self.handle).await?;
+        self.peer_names = fetch_peer_names(&mut self.handle, &self.config).await?;
+        log::debug!("Peers:\n{:#?}", self.other_devices);
+        Ok(())
+    }
+
+    /// Applies what can change while connected, and reports the rest.
+    async fn apply_config(&mut self, config: client_config::ClientConfig) {
+        // Only used on the next failover
+        self.config.fallback_masters = config.fallback_masters.clone();
+        self.config.master_order = config.master_order;
+
+        if config.log_level != self.config.log_level {
+            match crosslogging::set_config_level(config.log_level.as_deref()) {
+                Ok(()) => self.config.log_level = config.log_level.clone(),
+                Err(e) => log::error!("Failed to apply log level due to {}", e),
+            }
+        }
+
+        if config.group != self.config.group {
+            log::info!("Joining group '{}'", config.group);
+            let res = self
+                .handle
+                .request(MessageKind::JoinGroup, config.group.clone())
+                .await
+                .and_then(|reply| Ok(serde_json::from_str(&reply.body)?));
+
+            match res {
+                Ok(other_devices) => {
+                    self.other_devices = other_devices;
+                    self.config.group = config.group.clone();
+                    // Derived from the group as well
+                    self.history_key = open_history_key(&self.config);
+                }
+                Err(e) => log::error!("Failed to join group '{}' due to {}", config.group, e),
+            }
+        }
+
+        if config.filters != self.config.filters {
+            match filter::Filters::new(&config.filters) {
+                Ok(filters) => {
+                    self.filters = filters;
+                    self.config.filters = config.filters.clone();
+                }
+                Err(e) => log::error!("Failed to apply filters due to {}", e),
+            }
+        }
+
+        if config.sync != self.config.sync {
+            self.config.sync = config.sync.clone();
+            if self.peer_names.is_empty() {
+                self.refresh_peer_names().await;
+            }
+        }
+
+        let restart = self.config.restart_required(&config);
+        if !restart.is_empty() {
+            log::warn!(
+                "Changes to {} only apply after a restart",
+                restart.join(", ")
+            );
+        }
+    }
+
+    fn record(&self, origin: &str, content: &str) {
+        if let Some(history) = &self.history {
+            if let Err(e) = history.record(origin, history::TEXT, content) {
+                log::warn!("Failed to add to the history due to {}", e);
+            }
+        }
+    }
+
+    /// Clears expired content, unless something else was copied since.
+    /// Clears received ephemeral content from the clipboard once due, or
+    /// early on shutdown, as no one would clear it later.
+    async fn clear_ephemeral(&mut self) {
+        let Some((_, content)) = self.expiring.take() else {
+            return;
+        };
+
+        let res = async {
+            if self.clipboard.get().await? == content {
+                log::info!("Clearing ephemeral clipboard");
+                self.old_clipboard_content = String::new();
+                self.clipboard.set(String::new()).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        if let Err(e) = res.await {
+            log::warn!("Failed to clear ephemeral clipboard due to {}", e);
+        }
+    }
+
+    async fn password_manager_hint(&mut self) -> bool {
+        if !self.filters.wants_hints() {
+            return false;
+        }
+
+        for hint in filter::PASSWORD_MANAGER_HINTS {
+            match self.clipboard.offers(hint).await {
+                Ok(true) => return true,
+                Ok(false) => {}
+                Err(e) => log::debug!("Failed to look up clipboard formats due to {}", e),
+            }
+        }
+        false
+    }
+
+    async fn push_shared(&self, content: &str) {
+        let Some(shared) = &self.config.shared_history else {
+            return;
+        };
+
+        let entry = match (&self.history_key, &shared.password) {
+            (Some(key), _) => key.seal(history::TEXT, content).map(|content| HistoryPush {
+                content_type: history::TEXT.to_string(),
+                content,
+                encrypted: true,
+            }),
+            // Never pushed unencrypted if a password is set
+            (None, Some(_)) => return,
+            (None, None) => Ok(HistoryPush {
+                content_type: history::TEXT.to_string(),
+                content: content.to_string(),
+                encrypted: false,
+            }),
+        };
+        if let Err(e) = async { self.handle.push_history(&entry?).await }.await {
+            log::warn!("Failed to push to the shared history due to {}", e);
+        }
+    }
+
+    fn local_origin(&self) -> String {
+        self.config
+            .device_name
+            .clone()
+            .unwrap_or_else(pairing::hostname)
+    }
+
+    /// Runs a local copy through the filters, returning what to send and
+    /// its TTL, or the name of the filter blocking it.
+    /// Received ephemeral content keeps the TTL it has left when sent on.
+    fn outgoing(&self, content: &str, hinted: bool) -> Result<(String, Option<u64>), String> {
+        let (sent, ttl_secs) = match self.filters.apply(content, hinted) {
+            filter::Verdict::Sync {
+                content,
+                ephemeral: false,
+            } => (content, None),
+            filter::Verdict::Sync {
+                content,
+                ephemeral: true,
+            } => (content, Some(self.config.filters.ephemeral_ttl_secs)),
+            filter::Verdict::Blocked { by } => return Err(by),
+        };
+
+        let now = tokio::time::Instant::now();
+        let ttl_secs = match (
+            ttl_secs,
+            remaining_ttl(self.expiring.as_ref(), content, now),
+        ) {
+            (Some(ttl_secs), Some(remaining)) => Some(ttl_secs.min(remaining)),
+            (ttl_secs, remaining) => ttl_secs.or(remaining),
+        };
+        Ok((sent, ttl_secs))
+    }
+
+    /// Sends `content`, or the clipboard, like a copy but also in manual mode.
+    async fn push(&mut self, content: Option<String>, to: Option<String>) -> control::ControlReply {
+        match self.try_push(content, to).await {
+            Ok(devices) => control::ControlReply::Pushed { devices },
+            Err(e) => control::ControlReply::error(e),
+        }
+    }
+
+    async fn try_push(
+        &mut self,
+        content: Option<String>,
+        to: Option<String>,
+    ) -> anyhow::Result<Vec<String>> {
+        anyhow::ensure!(self.config.sync.sends(), "Only receiving, see sync.mode");
+
+        let (content, hinted) = match content {
+            Some(content) => (content, false),
+            None => {
+                let hinted = self.password_manager_hint().await;
+                (self.clipboard.get().await?, hinted)
+            }
+        };
+        let (content, ttl_secs) = self
+            .outgoing(&content, hinted)
+            .map_err(|by| anyhow::anyhow!("Blocked by filter '{}'", by))?;
+        anyhow::ensure!(
+            !self.config.sync.over_send_limit(content.len()),
+            "Clipboard of {} bytes is over sync.max_send_bytes",
+            content.len()
+        );
+
+        let targets = match to {
+            Some(name) => {
+                anyhow::ensure!(
+                    self.config.sync.allows(Some(&name)),
+                    "'{}' is not allowed by the sync rules",
+                    name
+                );
+                vec![self.resolve(&name).await?]
+            }
+            None => self
+                .other_devices
+                .iter()
+                .filter(|other| {
+                    let name = self.peer_names.get(*other).map(String::as_str);
+                    self.config.sync.allows(name)
+                })
+                .cloned()
+                .collect(),
+        };
+
+        let mut devices = Vec::new();
+        for id in targets {
+            self.handle
+                .send_with_ttl(
+                    id.clone(),
+                    MessageKind::Clipboard,
+                    content.clone(),
+                    ttl_secs,
+                )
+                .await?;
+            devices.push(self.peer_names.get(&id).cloned().unwrap_or(id.to_string()));
+        }
+        Ok(devices)
+    }
+
+    /// Asks a peer for its clipboard, `reply` is sent once it arrives.
+    async fn pull(
+        &mut self,
+        device: &str,
+        reply: tokio::sync::oneshot::Sender<control::ControlReply>,
+    ) {
+        let waiting = self.pulling.as_ref().is_some_and(|(_, due, waiting)| {
+            *due > tokio::time::Instant::now() && !waiting.is_closed()
+        });
+        if waiting {
+            let _ = reply.send(control::ControlReply::error(
+                "Another pull is still waiting for an answer",
+            ));
+            return;
+        }
+
+        match self.request_pull(device).await {
+            Ok(id) => {
+                let due = tokio::time::Instant::now() + PULL_TIMEOUT;
+                self.pulling = Some((id, due, reply));
+            }
+            Err(e) => {
+                let _ = reply.send(control::ControlReply::error(e));
+            }
+        }
+    }
+
+    async fn request_pull(&mut self, device: &str) -> anyhow::Result<ID> {
+        let sync = &self.config.sync;
+        anyhow::ensure!(sync.receives(), "Only sending, see sync.mode");
+        anyhow::ensure!(
+            sync.allows(Some(device)),
+            "'{}' is not allowed by the sync rules",
+            device
+        );
+
+        let id = self.resolve(device).await?;
+        self.handle
+            .send(id.clone(), MessageKind::PullClipboard, String::new())
+            .await?;
+        Ok(id)
+    }
+
+    /// Answers `PullClipboard` with the clipboard, or a `Notice` on refusal.
+    async fn share_clipboard(&mut self, to: &ID) -> anyhow::Result<()> {
+        let (kind, body, ttl_secs) = match self.shareable_clipboard(to).await {
+            Ok((content, ttl_secs)) => (MessageKind::Clipboard, content, ttl_secs),
+            Err(e) => {
+                log::info!("Refused to share clipboard with {:?}, {}", to, e);
+                let notice = format!(
+                    "'{}' refused to share its clipboard, {}",
+                    self.local_origin(),
+                    e
+                );
+                (MessageKind::Notice, notice, None)
+            }
+        };
+        self.handle
+            .send_with_ttl(to.clone(), kind, body, ttl_secs)
+            .await
+    }
+
+    async fn shareable_clipboard(&mut self, to: &ID) -> anyhow::Result<(String, Option<u64>)> {
+        let name = self.peer_names.get(to).map(String::as_str);
+        anyhow::ensure!(self.config.sync.sends(), "it is only receiving");
+        anyhow::ensure!(!self.config.sync.manual, "it only sends by `client push`");
+        anyhow::ensure!(
+            self.config.sync.allows(name),
+            "not allowed by its sync rules"
+        );
+
+        let hinted = self.password_manager_hint().await;
+        let content = self.clipboard.get().await?;
+        let (content, ttl_secs) = self
+            .outgoing(&content, hinted)
+            .map_err(|by| anyhow::anyhow!("blocked by filter '{}'", by))?;
+        anyhow::ensure!(
+            !self.config.sync.over_send_limit(content.len()),
+            "over its sync.max_send_bytes"
+        );
+        Ok((content, ttl_secs))
+    }
+
+    /// The peer named `name`, asking for the names again if it's unknown.
+    async fn resolve(&mut self, name: &str) -> anyhow::Result<ID> {
+        let find = |names: &std::collections::HashMap<ID, String>| {
+            names
+                .iter()
+                .find(|(_, peer)| *peer == name)
+                .map(|(id, _)| id.clone())
+        };
+        if let Some(id) = find(&self.peer_names) {
+            return Ok(id);
+        }
+
+        let peers = self.handle.peers().await?;
+        self.peer_names = peers.into_iter().map(|peer| (peer.id, peer.name)).collect();
+        find(&self.peer_names).ok_or_else(|| anyhow::anyhow!("No device named '{}'", name))
+    }
+
+    /// Asks for the names of all peers, the reply is handled by `handle_message`.
+    async fn refresh_peer_names(&mut self) {
+        match fetch_peer_names(&mut self.handle, &self.config).await {
+            Ok(peer_names) => self.peer_names = peer_names,
+            Err(e) => log::warn!("Failed to fetch peer names due to {}", e),
+        }
+    }
+
+    /// Whether a clipboard of another device passes the sync rules.
+    fn accepts(&self, msg: &Message) -> bool {
+        let sync = &self.config.sync;
+        let from = &msg.tail.from;
+        if !sync.receives() {
+            log::debug!("Ignored clipboard of {:?}, only sending", from);
+            return false;
+        }
+        if sync
+            .max_receive_bytes
+            .is_some_and(|max| msg.body.len() > max)
+        {
+            log::info!(
+                "Ignored clipboard of {} bytes from {:?}, over sync.max_receive_bytes",
+                msg.body.len(),
+                from
+            );
+            return false;
+        }
+        if !sync.allows(self.peer_names.get(from).map(String::as_str)) {
+            log::info!("Ignored clipboard of {:?}, not allowed by sync rules", from);
+            return false;
+        }
+        true
+    }
+
     async fn handle_message(&mut self, msg: Message) -> anyhow::Result<()> {
         match msg.header.kind {
-            MessageKind::Clipboard => self.clipboard.set(msg.body).await?,
+            MessageKind::Clipboard => {
+                let pulled = self.pulling.take_if(|(id, _, _)| *id == msg.tail.from);
+                if !self.accepts(&msg) {
+                    if let Some((_, _, reply)) = pulled {
+                        let _ =
+                            reply.send(control::ControlReply::error("Ignored by the sync rules"));
+                    }
+                    return Ok(());
+                }
+                if let Some((_, _, reply)) = pulled {
+                    let bytes = msg.body.len();
+                    let _ = reply.send(control::ControlReply::Pulled { bytes });
+                }
+
+                match msg.header.ttl_secs {
+                    Some(ttl_secs) => {
+                        let due =
+                            tokio::time::Instant::now() + std::time::Duration::from_secs(ttl_secs);
+                        self.expiring = Some((due, msg.body.clone()));
+                    }
+                    None => {
+                        let origin = match self.peer_names.get(&msg.tail.from) {
+                            Some(name) => name.clone(),
+                            None => msg.tail.from.to_string(),
+                        };
+                        self.record(&origin, &msg.body);
+                        self.expiring = None;
+                    }
+                }
+                // Not to be taken for a local copy and sent back
+                self.old_clipboard_content = msg.body.clone();
+                self.clipboard.set(msg.body).await?
+            }
             MessageKind::NewRegDevice => {
                 self.other_devices.push(serde_json::from_str(&msg.body)?);
+                self.refresh_peer_names().await;
             }
             MessageKind::ClosedRegDevice => {
-                remove_on_match(&mut self.other_devices, &serde_json::from_str(&msg.body)?)
+                let id = serde_json::from_str(&msg.body)?;
+                self.peer_names.remove(&id);
+                remove_on_match(&mut self.other_devices, &id)
+            }
+            MessageKind::Error => {
+                log::warn!(
+                    "Master refused message: {}",
+                    ErrorReply::from_message(&msg)?
+                )
             }
+            MessageKind::Notice => {
+                log::info!("Notice from {:?}: {}", msg.tail.from, msg.body);
+                if let Some((_, _, reply)) = self.pulling.take_if(|(id, _, _)| *id == msg.tail.from)
+                {
+                    let _ = reply.send(control::ControlReply::error(msg.body));
+                }
+            }
+            MessageKind::PullClipboard => self.share_clipboard(&msg.tail.from).await?,
             _ => {}
         }
 
@@ -110,19 +957,25 @@ where
 
 #[cfg(target_os = "linux")]
 impl Client<copypasta::ClipboardContext> {
-    async fn new(mut handle: CrossHandle) -> anyhow::Result<Self> {
-        handle
-            .send(ID::Master, MessageKind::GetRegDevices, String::new())
-            .await?;
-
-        let other_devices_msg = handle.recv().await.unwrap();
-        let other_devices: Vec<ID> = serde_json::from_str(&other_devices_msg.body)?;
+    async fn new(
+        mut handle: CrossHandle,
+        config: client_config::ClientConfig,
+    ) -> anyhow::Result<Self> {
+        let other_devices = fetch_devices(&mut handle).await?;
+        let peer_names = fetch_peer_names(&mut handle, &config).await?;
         let clipboard = AsyncClipboard::new().await?;
 
         log::debug!("Peers:\n{:#?}", other_devices);
 
         Ok(Client {
+            history: open_history(&config),
+            history_key: open_history_key(&config),
+            filters: filter::Filters::new(&config.filters)?,
+            expiring: None,
+            peer_names,
+            pulling: None,
             handle,
+            config,
             clipboard,
             old_clipboard_content: String::new(),
             other_devices,
@@ -132,17 +985,23 @@ impl Client<copypasta::ClipboardContext> {
 
 #[cfg(target_os = "windows")]
 impl Client<clipboard::WindowsClipboardWrapper> {
-    async fn new(mut handle: CrossHandle) -> anyhow::Result<Self> {
-        handle
-            .send(ID::Master, MessageKind::GetRegDevices, String::new())
-            .await?;
-
-        let other_devices_msg = handle.recv().await.unwrap();
-        let other_devices: Vec<ID> = serde_json::from_str(&other_devices_msg.body)?;
+    async fn new(
+        mut handle: CrossHandle,
+        config: client_config::ClientConfig,
+    ) -> anyhow::Result<Self> {
+        let other_devices = fetch_devices(&mut handle).await?;
+        let peer_names = fetch_peer_names(&mut handle, &config).await?;
         let clipboard = AsyncClipboard::new().await?;
 
         Ok(Client {
+            history: open_history(&config),
+            history_key: open_history_key(&config),
+            filters: filter::Filters::new(&config.filters)?,
+            expiring: None,
+            peer_names,
+            pulling: None,
             handle,
+            config,
             clipboard,
             old_clipboard_content: String::new(),
             other_devices,
@@ -150,6 +1009,66 @@ impl Client<clipboard::WindowsClipboardWrapper> {
     }
 }
 
+/// Waits until `due`, or forever without one.
+async fn until(due: Option<tokio::time::Instant>) {
+    match due {
+        Some(due) => tokio::time::sleep_until(due).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Seconds until `expiring` is cleared, if it still holds `content`.
+/// Rounded up and at least 1, so the content is never sent on without a TTL.
+fn remaining_ttl(
+    expiring: Option<&(tokio::time::Instant, String)>,
+    content: &str,
+    now: tokio::time::Instant,
+) -> Option<u64> {
+    let (due, expiring) = expiring?;
+    if expiring != content {
+        return None;
+    }
+
+    let left = due.saturating_duration_since(now);
+    Some((left.as_secs() + u64::from(left.subsec_nanos() > 0)).max(1))
+}
+
+/// Nothing is pushed to the shared history if the key can't be derived.
+fn open_history_key(config: &client_config::ClientConfig) -> Option<client_lib::e2e::HistoryKey> {
+    config
+        .history_key()
+        .map_err(|e| log::error!("Not pushing to the shared history due to {}", e))
+        .ok()
+        .flatten()
+}
+
+/// Runs without a history if it can't be opened.
+fn open_history(config: &client_config::ClientConfig) -> Option<history::History> {
+    let res = history::History::open(config.history.as_ref()?);
+    res.map_err(|e| log::warn!("Running without history, failed to open it due to {}", e))
+        .ok()
+}
+
+/// Only asks the Master if the sync rules need them.
+async fn fetch_peer_names(
+    handle: &mut CrossHandle,
+    config: &client_config::ClientConfig,
+) -> anyhow::Result<std::collections::HashMap<ID, String>> {
+    if !config.sync.needs_names() {
+        return Ok(std::collections::HashMap::new());
+    }
+
+    let peers = handle.peers().await?;
+    Ok(peers.into_iter().map(|peer| (peer.id, peer.name)).collect())
+}
+
+async fn fetch_devices(handle: &mut CrossHandle) -> anyhow::Result<Vec<ID>> {
+    let reply = handle
+        .request(MessageKind::GetRegDevices, String::new())
+        .await?;
+    Ok(serde_json::from_str(&reply.body)?)
+}
+
 fn remove_on_match(v: &mut Vec<ID>, target: &ID) {
     for (index, item) in v.clone().iter().enumerate() {
         if item == target {
@@ -157,3 +1076,23 @@ fn remove_on_match(v: &mut Vec<ID>, target: &ID) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn forwards_remaining_ttl() {
+        let now = tokio::time::Instant::now();
+        let expiring = (now + Duration::from_millis(29_500), "secret".to_string());
+
+        assert_eq!(remaining_ttl(Some(&expiring), "secret", now), Some(30));
+        assert_eq!(remaining_ttl(Some(&expiring), "copied since", now), None);
+        assert_eq!(remaining_ttl(None, "secret", now), None);
+
+        // Due but not cleared yet
+        let later = now + Duration::from_secs(60);
+        assert_eq!(remaining_ttl(Some(&expiring), "secret", later), Some(1));
+    }
+}
@@ -0,0 +1,91 @@
+use crate::client_config::ClientConfig;
+use client_lib::*;
+use cross_messages::*;
+
+/// Registers as this device and prints a pairing code for a new one.
+pub async fn pair_code(layers: &crossconfig::Layers) -> anyhow::Result<()> {
+    let config = ClientConfig::load(layers)?;
+    let (mut client, mut handle) =
+        CrossClient::connect(&config.transport_config(), &config.master_addr())
+            .await?
+            .pin_fingerprint(config.master_fingerprint.clone())
+            .register(config.register_request())
+            .await?;
+
+    let client_handle = tokio::spawn(async move { client.run().await });
+
+    let reply = handle
+        .request(MessageKind::PairingCode, String::new())
+        .await?;
+    let code: PairingCode = serde_json::from_str(&reply.body)?;
+
+    println!(
+        "Pairing code: {} (valid for {}s)\nRun `client pair {}` on the new device",
+        code.code, code.expires_in_secs, code.code
+    );
+
+    handle
+        .send(ID::Master, MessageKind::Close, String::new())
+        .await?;
+    client_handle.await??;
+    Ok(())
+}
+
+/// Redeems `code` and writes the received credentials into the config.
+/// Only the file is updated, values from env vars and flags are not saved.
+pub async fn pair(
+    layers: &crossconfig::Layers,
+    code: String,
+    master: Option<String>,
+    name: Option<String>,
+) -> anyhow::Result<()> {
+    let path = layers
+        .file()
+        .ok_or_else(|| anyhow::anyhow!("No config directory, pass --config to pair"))?
+        .to_string_lossy()
+        .to_string();
+    // Only a missing config is replaced, one that fails to parse is kept
+    let existing = match std::path::Path::new(&path).try_exists()? {
+        true => Some(ClientConfig::get_from(&path)?),
+        false => None,
+    };
+    let mut config = match (master, existing) {
+        (Some(addr), Some(mut existing)) => {
+            existing.set_master(&addr)?;
+            existing
+        }
+        (Some(addr), None) => ClientConfig::new(&addr)?,
+        (None, Some(existing)) => existing,
+        (None, None) => {
+            return Err(anyhow::anyhow!(
+                "No Master address given and no config at {}",
+                path
+            ))
+        }
+    };
+
+    let device = name
+        .or_else(|| config.device_name.clone())
+        .unwrap_or_else(hostname);
+
+    let grant = CrossClient::connect(&config.transport_config(), &config.master_addr())
+        .await?
+        .pair(PairRedeem { code, device })
+        .await?;
+
+    log::info!("Paired as '{}' in group '{}'", grant.device, grant.group);
+    config.apply_grant(grant);
+    config.save(&path)?;
+
+    println!("Paired, wrote config to {}", path);
+    Ok(())
+}
+
+/// The name devices are known by unless `device_name` is set.
+pub(crate) fn hostname() -> String {
+    let name = gethostname::gethostname().to_string_lossy().into_owned();
+    match name.is_empty() {
+        true => "crosslive-device".to_string(),
+        false => name,
+    }
+}
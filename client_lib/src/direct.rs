@@ -0,0 +1,364 @@
+//! Direct connections between devices, set up with the help of the Master.
+//!
+//! Before relaying a large message, the sender offers a direct connection
+//! to the target with a `Rendezvous` through the Master, which adds the
+//! address it sees the sender at. Of the two devices, the one with the lower
+//! ID connects to the other, presenting the secret of its offer. Once
+//! connected, large messages go direct and everything else is still relayed.
+//! If the direct connection fails, messages are relayed again.
+//!
+//! Direct messages skip the policy and rate limits of the Master. Connections
+//! are closed once the Master reports the other device gone, the group is
+//! changed or the Master withdraws them, e.g. as its policy changed. A policy
+//! denying `Rendezvous` keeps devices from connecting direct at all.
+
+use cross_messages::*;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Messages with at least this many bytes of body go direct by default.
+pub const DEFAULT_MIN_BYTES: usize = 64 * 1024;
+
+/// Time after which a failed direct connection is offered again.
+const RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Time given to a candidate to accept and complete the handshake.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub(crate) enum Event {
+    /// An unverified connection and its first message.
+    Incoming(MessageStream, Message),
+    Connected(ID, MessageStream),
+    Received(ID, Message),
+    /// The connection broke while sending the message.
+    Failed(ID, Message),
+    Closed(ID),
+}
+
+pub(crate) struct Direct {
+    id: ID,
+    listener: MessageListener,
+    port: u16,
+    /// Address the device reaches the Master from, usually on the LAN.
+    /// Unknown on transports without socket addresses.
+    local_ip: Option<std::net::IpAddr>,
+    min_bytes: usize,
+    peers: HashMap<ID, mpsc::Sender<Message>>,
+    /// Secrets of our offers, by the device they were sent to.
+    secrets: HashMap<ID, String>,
+    offered: HashMap<ID, Instant>,
+    events_tx: mpsc::Sender<Event>,
+    events_rx: mpsc::Receiver<Event>,
+}
+
+impl Direct {
+    pub(crate) async fn new(
+        id: ID,
+        master_stream: &MessageStream,
+        min_bytes: usize,
+    ) -> std::io::Result<Self> {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let port = listener.local_addr()?.port();
+        let (events_tx, events_rx) = mpsc::channel(16);
+        log::info!("Accepting direct connections on port {}", port);
+
+        Ok(Direct {
+            id,
+            listener: MessageListener::with(listener),
+            port,
+            local_ip: master_stream.local_addr().ok().map(|addr| addr.ip()),
+            min_bytes,
+            peers: HashMap::new(),
+            secrets: HashMap::new(),
+            offered: HashMap::new(),
+            events_tx,
+            events_rx,
+        })
+    }
+
+    /// Waits for the next connection or message of a direct peer.
+    pub(crate) async fn next(&mut self) -> std::io::Result<Event> {
+        loop {
+            tokio::select! {
+                res = self.listener.accept() => {
+                    let (mut stream, addr) = res?;
+                    let events = self.events_tx.clone();
+                    tokio::spawn(async move {
+                        match tokio::time::timeout(CONNECT_TIMEOUT, stream.recv()).await {
+                            Ok(Ok(hello)) => {
+                                let _ = events.send(Event::Incoming(stream, hello)).await;
+                            }
+                            _ => log::debug!("No hello from direct connection {}", addr),
+                        }
+                    });
+                }
+
+                Some(event) = self.events_rx.recv() => return Ok(event),
+            }
+        }
+    }
+
+    /// Sends `msg` over a direct connection if it is large and one is open.
+    /// Returns the message if it has to be relayed by the Master instead.
+    pub(crate) async fn send(&mut self, msg: Message) -> Option<Message> {
+        if msg.body.len() < self.min_bytes {
+            return Some(msg);
+        }
+
+        let Some(peer) = self.peers.get(&msg.header.target) else {
+            return Some(msg);
+        };
+
+        log::debug!(
+            "Sending {:?} direct to {:?}",
+            msg.header.kind,
+            msg.header.target
+        );
+        match peer.send(msg).await {
+            Ok(()) => None,
+            Err(mpsc::error::SendError(msg)) => {
+                self.peers.remove(&msg.header.target);
+                Some(msg)
+            }
+        }
+    }
+
+    /// An offer to relay to the target of `msg`, if a direct connection to it
+    /// is worth trying.
+    pub(crate) fn offer_for(&mut self, msg: &Message) -> serde_json::Result<Option<Message>> {
+        let target = &msg.header.target;
+        let recently = self
+            .offered
+            .get(target)
+            .is_some_and(|offered| offered.elapsed() < RETRY_AFTER);
+
+        if msg.body.len() < self.min_bytes
+            || !matches!(target, ID::Slave(_))
+            || self.peers.contains_key(target)
+            || recently
+        {
+            return Ok(None);
+        }
+
+        log::info!("Offering a direct connection to {:?}", target);
+        self.offered.insert(target.clone(), Instant::now());
+        self.offer(target.clone()).map(Some)
+    }
+
+    fn offer(&mut self, target: ID) -> serde_json::Result<Message> {
+        let secret = uuid::Uuid::new_v4().to_string();
+        self.secrets.insert(target.clone(), secret.clone());
+
+        let offer = RendezvousOffer {
+            port: self.port,
+            candidates: self
+                .local_ip
+                .map(|ip| SocketAddr::new(ip, self.port))
+                .into_iter()
+                .collect(),
+            secret,
+        };
+
+        Ok(Message {
+            header: Header {
+                kind: MessageKind::Rendezvous,
+                target,
+                ttl_secs: None,
+            },
+            body: serde_json::to_string(&offer)?,
+            tail: Tail {
+                from: self.id.clone(),
+            },
+        })
+    }
+
+    /// Handles an offer relayed by the Master. Returns our own offer to relay
+    /// back, if the other device is the one to connect.
+    pub(crate) fn on_offer(&mut self, msg: &Message) -> anyhow::Result<Option<Message>> {
+        let from = msg.tail.from.clone();
+        if self.peers.contains_key(&from) {
+            return Ok(None);
+        }
+
+        let offer: RendezvousOffer = serde_json::from_str(&msg.body)?;
+
+        // Of two devices, only the one with the lower ID connects
+        if self.id.to_string() > from.to_string() {
+            self.offered.insert(from.clone(), Instant::now());
+            return Ok(Some(self.offer(from)?));
+        }
+
+        let hello = Message {
+            header: Header {
+                kind: MessageKind::Rendezvous,
+                target: from.clone(),
+                ttl_secs: None,
+            },
+            body: offer.secret,
+            tail: Tail {
+                from: self.id.clone(),
+            },
+        };
+        let events = self.events_tx.clone();
+        tokio::spawn(async move {
+            for addr in offer.candidates {
+                match tokio::time::timeout(CONNECT_TIMEOUT, connect(addr, hello.clone())).await {
+                    Ok(Ok(stream)) => {
+                        log::info!("Connected direct to {:?} at {}", from, addr);
+                        let _ = events.send(Event::Connected(from, stream)).await;
+                        return;
+                    }
+                    Ok(Err(e)) => log::debug!("Direct candidate {} failed due to {}", addr, e),
+                    Err(_) => log::debug!("Direct candidate {} timed out", addr),
+                }
+            }
+            log::info!("No direct connection to {:?}, relaying", from);
+        });
+
+        Ok(None)
+    }
+
+    /// Applies an event, returning a message to deliver to the handle or to
+    /// relay through the Master.
+    pub(crate) async fn handle(&mut self, event: Event) -> Option<Routed> {
+        match event {
+            Event::Incoming(mut stream, hello) => {
+                let from = hello.tail.from.clone();
+                let valid = hello.header.kind == MessageKind::Rendezvous
+                    && hello.header.target == self.id
+                    && self.secrets.get(&from) == Some(&hello.body);
+                if !valid {
+                    log::warn!("Refused direct connection claiming to be {:?}", from);
+                    return None;
+                }
+
+                self.secrets.remove(&from);
+                let ack = Message {
+                    header: Header {
+                        kind: MessageKind::Rendezvous,
+                        target: from.clone(),
+                        ttl_secs: None,
+                    },
+                    body: String::new(),
+                    tail: Tail {
+                        from: self.id.clone(),
+                    },
+                };
+                if stream.send(ack).await.is_ok() {
+                    log::info!("Accepted direct connection from {:?}", from);
+                    self.add_peer(from, stream);
+                }
+                None
+            }
+
+            Event::Connected(id, stream) => {
+                self.add_peer(id, stream);
+                None
+            }
+
+            Event::Received(id, msg) => {
+                if msg.tail.from != id || msg.header.target != self.id {
+                    log::warn!("Dropped direct message of {:?} with wrong address", id);
+                    return None;
+                }
+                Some(Routed::Deliver(msg))
+            }
+
+            Event::Failed(id, msg) => {
+                log::warn!("Direct connection to {:?} failed, relaying", id);
+                self.peers.remove(&id);
+                Some(Routed::Relay(msg))
+            }
+
+            Event::Closed(id) => {
+                if self.peers.remove(&id).is_some() {
+                    log::info!("Direct connection to {:?} closed", id);
+                }
+                None
+            }
+        }
+    }
+
+    /// Closes the connection to `id` and forgets our offers to it.
+    pub(crate) fn close(&mut self, id: &ID) {
+        self.secrets.remove(id);
+        self.offered.remove(id);
+        // Dropping the sender ends the connection
+        if self.peers.remove(id).is_some() {
+            log::info!("Closed direct connection to {:?}", id);
+        }
+    }
+
+    pub(crate) fn close_all(&mut self) {
+        let ids = self.peers.keys().cloned().collect::<Vec<_>>();
+        for id in ids {
+            self.close(&id);
+        }
+        self.secrets.clear();
+        self.offered.clear();
+    }
+
+    fn add_peer(&mut self, id: ID, stream: MessageStream) {
+        let (tx, rx) = mpsc::channel(16);
+        self.peers.insert(id.clone(), tx);
+        tokio::spawn(peer(id, stream, rx, self.events_tx.clone()));
+    }
+}
+
+pub(crate) enum Routed {
+    Deliver(Message),
+    Relay(Message),
+}
+
+async fn connect(addr: SocketAddr, hello: Message) -> anyhow::Result<MessageStream> {
+    let mut stream = MessageStream::connect(addr).await?;
+    let target = hello.header.target.clone();
+    stream.send(hello).await?;
+
+    let ack = stream.recv().await?;
+    anyhow::ensure!(
+        ack.header.kind == MessageKind::Rendezvous && ack.tail.from == target,
+        "Unexpected reply from {:?}",
+        ack.tail.from
+    );
+    Ok(stream)
+}
+
+/// Moves messages between a direct peer and the client, until either side is gone.
+async fn peer(
+    id: ID,
+    mut stream: MessageStream,
+    mut out: mpsc::Receiver<Message>,
+    events: mpsc::Sender<Event>,
+) {
+    loop {
+        tokio::select! {
+            res = stream.recv() => match res {
+                Ok(msg) => {
+                    if events.send(Event::Received(id.clone(), msg)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    log::debug!("Direct connection to {:?} closed due to {}", id, e);
+                    break;
+                }
+            },
+
+            res = out.recv() => match res {
+                Some(msg) => {
+                    if let Err(e) = stream.send(msg.clone()).await {
+                        log::debug!("Direct connection to {:?} failed due to {}", id, e);
+                        let _ = events.send(Event::Failed(id, msg)).await;
+                        return;
+                    }
+                }
+                None => break,
+            },
+        }
+    }
+
+    let _ = events.send(Event::Closed(id)).await;
+}
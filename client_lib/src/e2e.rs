@@ -0,0 +1,76 @@
+//! End-to-end encryption of shared history entries. The Master only ever
+//! stores what `HistoryKey::seal` returns. The group and content type are
+//! authenticated along, so the Master can't pass an entry off as another.
+
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+/// Bytes of the nonce prepended to every sealed entry.
+const NONCE_LEN: usize = 24;
+
+/// Key derived from a passphrase shared by the devices of a group.
+pub struct HistoryKey {
+    cipher: XChaCha20Poly1305,
+    group: String,
+}
+
+impl HistoryKey {
+    /// The same passphrase and group give the same key on every device.
+    pub fn derive(passphrase: &str, group: &str) -> anyhow::Result<Self> {
+        let salt = format!("crosslive-history/{}", group);
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut key)
+            .map_err(|e| anyhow::anyhow!("Failed to derive history key: {}", e))?;
+
+        Ok(HistoryKey {
+            cipher: XChaCha20Poly1305::new(&key.into()),
+            group: group.to_string(),
+        })
+    }
+
+    fn aad(&self, content_type: &str) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&(&self.group, content_type))?)
+    }
+
+    /// Encrypts `content` with a random nonce, as base64.
+    pub fn seal(&self, content_type: &str, content: &str) -> anyhow::Result<String> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: content.as_bytes(),
+            aad: &self.aad(content_type)?,
+        };
+        let sealed = self
+            .cipher
+            .encrypt(&nonce, payload)
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt history entry"))?;
+
+        let mut bytes = nonce.to_vec();
+        bytes.extend_from_slice(&sealed);
+        Ok(STANDARD.encode(bytes))
+    }
+
+    /// Fails for entries sealed with another key or as another content type.
+    pub fn open(&self, content_type: &str, sealed: &str) -> anyhow::Result<String> {
+        let bytes = STANDARD.decode(sealed)?;
+        if bytes.len() < NONCE_LEN {
+            return Err(anyhow::anyhow!("History entry is too short to be sealed"));
+        }
+
+        let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: sealed,
+            aad: &self.aad(content_type)?,
+        };
+        let content = self
+            .cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map_err(|_| {
+                anyhow::anyhow!("History entry was sealed with another key or content type")
+            })?;
+        Ok(String::from_utf8(content)?)
+    }
+}
@@ -0,0 +1,455 @@
+//! Serverless mode for clients on the same LAN.
+//!
+//! Clients announce themselves by UDP broadcast and connect to each other
+//! directly, exchanging the same `Message`s as through a Master. The
+//! `CrossHandle` behaves as if a Master was there: peers come and go with
+//! `NewRegDevice` and `ClosedRegDevice`, and `GetRegDevices`, `GetPeers`,
+//! `JoinGroup` and `Close` are answered by the node itself.
+//!
+//! Any client on the LAN announcing the same group is trusted.
+
+use crate::CrossHandle;
+use cross_messages::*;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+pub const DEFAULT_DISCOVERY_PORT: u16 = 7010;
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Time given to a peer to connect and complete the handshake.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub struct LanConfig {
+    pub group: String,
+    pub device: Option<String>,
+    /// Where announcements are sent, usually a broadcast address.
+    /// Its port is also the one announcements are received on.
+    pub discovery_addr: SocketAddr,
+}
+
+impl LanConfig {
+    pub fn new(group: &str) -> Self {
+        LanConfig {
+            group: group.to_string(),
+            device: None,
+            discovery_addr: SocketAddr::from((Ipv4Addr::BROADCAST, DEFAULT_DISCOVERY_PORT)),
+        }
+    }
+}
+
+enum Event {
+    Connected(LanAnnounce, MessageStream),
+    Failed(ID),
+    Received(ID, Message),
+    Closed(ID),
+}
+
+/// Connects to the peers on the LAN, like `RegisteredClient` does to the Master.
+pub struct LanNode {
+    config: LanConfig,
+    id: ID,
+    port: u16,
+    discovery: UdpSocket,
+    listener: MessageListener,
+    peers: HashMap<ID, mpsc::Sender<Message>>,
+    /// Device names the peers announced.
+    names: HashMap<ID, String>,
+    dialing: HashSet<ID>,
+    events_tx: mpsc::Sender<Event>,
+    events_rx: mpsc::Receiver<Event>,
+    tx: mpsc::Sender<Message>,
+    rx: mpsc::Receiver<Message>,
+}
+
+impl LanNode {
+    pub async fn start(config: LanConfig) -> anyhow::Result<(LanNode, CrossHandle)> {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let port = listener.local_addr()?.port();
+        let discovery = discovery_socket(config.discovery_addr.port())?;
+
+        let id = ID::new_slave();
+        log::info!(
+            "Starting LAN mode as {:?} in group '{}', announcing to {}",
+            id,
+            config.group,
+            config.discovery_addr
+        );
+
+        let (reg_tx, rx) = mpsc::channel::<Message>(16);
+        let (tx, reg_rx) = mpsc::channel::<Message>(16);
+        let (events_tx, events_rx) = mpsc::channel(16);
+
+        let handle = CrossHandle {
+            tx,
+            rx,
+            pending: VecDeque::new(),
+            registered_id: id.clone(),
+        };
+
+        let node = LanNode {
+            config,
+            id,
+            port,
+            discovery,
+            listener: MessageListener::with(listener),
+            peers: HashMap::new(),
+            names: HashMap::new(),
+            dialing: HashSet::new(),
+            events_tx,
+            events_rx,
+            tx: reg_tx,
+            rx: reg_rx,
+        };
+
+        Ok((node, handle))
+    }
+
+    /// Runs until the `CrossHandle` sends `Close` or is dropped.
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        let mut announce = tokio::time::interval(ANNOUNCE_INTERVAL);
+        let mut datagram = [0; 1024];
+
+        loop {
+            tokio::select! {
+                _ = announce.tick() => {
+                    let body = serde_json::to_vec(&self.announce())?;
+                    if let Err(e) = self.discovery.send_to(&body, self.config.discovery_addr).await {
+                        log::warn!("Failed to announce on the LAN due to {}", e);
+                    }
+                }
+
+                res = self.discovery.recv_from(&mut datagram) => {
+                    let (len, from) = res?;
+                    match serde_json::from_slice::<LanAnnounce>(&datagram[..len]) {
+                        Ok(announce) => self.discovered(announce, from),
+                        Err(e) => log::debug!("Ignored datagram from {} due to {}", from, e),
+                    }
+                }
+
+                res = self.listener.accept() => {
+                    let (stream, addr) = res?;
+                    let hello = self.announce();
+                    let events = self.events_tx.clone();
+                    tokio::spawn(async move {
+                        match tokio::time::timeout(HELLO_TIMEOUT, accept(stream, hello)).await {
+                            Ok(Ok((theirs, stream))) => {
+                                let _ = events.send(Event::Connected(theirs, stream)).await;
+                            }
+                            Ok(Err(e)) => log::warn!("Refused LAN peer {} due to {}", addr, e),
+                            Err(_) => log::warn!("LAN peer {} timed out", addr),
+                        }
+                    });
+                }
+
+                Some(event) = self.events_rx.recv() => self.handle_event(event).await?,
+
+                res = self.rx.recv() => {
+                    // The CrossHandle was dropped
+                    let Some(msg) = res else { return Ok(()) };
+                    if !self.send(msg).await? {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn announce(&self) -> LanAnnounce {
+        LanAnnounce {
+            id: self.id.clone(),
+            group: self.config.group.clone(),
+            device: self.config.device.clone(),
+            port: self.port,
+        }
+    }
+
+    /// Connects to a new peer, unless the peer is expected to connect to us.
+    fn discovered(&mut self, announce: LanAnnounce, from: SocketAddr) {
+        if announce.id == self.id
+            || announce.group != self.config.group
+            || self.peers.contains_key(&announce.id)
+            || self.dialing.contains(&announce.id)
+        {
+            return;
+        }
+
+        // Of two peers, only the one with the lower ID connects
+        if self.id.to_string() > announce.id.to_string() {
+            return;
+        }
+
+        log::info!("Found LAN peer {:?} at {}", announce.id, from.ip());
+        self.dialing.insert(announce.id.clone());
+        let addr = SocketAddr::new(from.ip(), announce.port);
+        let hello = self.announce();
+        let events = self.events_tx.clone();
+        tokio::spawn(async move {
+            let theirs = announce.clone();
+            let id = announce.id.clone();
+            let event = match tokio::time::timeout(HELLO_TIMEOUT, dial(addr, hello, announce)).await
+            {
+                Ok(Ok(stream)) => Event::Connected(theirs, stream),
+                Ok(Err(e)) => {
+                    log::warn!("Failed to connect to LAN peer {} due to {}", addr, e);
+                    Event::Failed(id)
+                }
+                Err(_) => {
+                    log::warn!("LAN peer {} timed out", addr);
+                    Event::Failed(id)
+                }
+            };
+            let _ = events.send(event).await;
+        });
+    }
+
+    async fn handle_event(&mut self, event: Event) -> anyhow::Result<()> {
+        match event {
+            Event::Connected(theirs, stream) => {
+                let id = theirs.id;
+                self.dialing.remove(&id);
+                // Dialed or accepted before a JoinGroup
+                if self.peers.contains_key(&id) || theirs.group != self.config.group {
+                    return Ok(());
+                }
+
+                let name = theirs.device.unwrap_or_else(|| id.to_string());
+                self.names.insert(id.clone(), name);
+
+                log::info!("Connected to LAN peer {:?}", id);
+                let (out_tx, out_rx) = mpsc::channel(16);
+                self.peers.insert(id.clone(), out_tx);
+                tokio::spawn(peer(id.clone(), stream, out_rx, self.events_tx.clone()));
+                self.to_handle(MessageKind::NewRegDevice, serde_json::to_string(&id)?)
+                    .await?;
+            }
+
+            Event::Failed(id) => {
+                self.dialing.remove(&id);
+            }
+
+            Event::Received(id, msg) => {
+                if !self.peers.contains_key(&id) {
+                    log::debug!("Dropped message of former LAN peer {:?}", id);
+                    return Ok(());
+                }
+                if msg.tail.from != id || msg.header.target != self.id {
+                    log::warn!("Dropped message of LAN peer {:?} with wrong address", id);
+                    return Ok(());
+                }
+                log::info!("New {:?} from LAN peer {:?}", msg.header.kind, id);
+                self.tx.send(msg).await?;
+            }
+
+            Event::Closed(id) => {
+                // Peers of an old group were already forgotten
+                self.names.remove(&id);
+                if self.peers.remove(&id).is_some() {
+                    log::info!("Lost LAN peer {:?}", id);
+                    self.to_handle(MessageKind::ClosedRegDevice, serde_json::to_string(&id)?)
+                        .await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends a message of the handle to its target.
+    /// Returns false once the handle closed the node.
+    async fn send(&mut self, msg: Message) -> anyhow::Result<bool> {
+        match msg.header.target.clone() {
+            ID::Master => match msg.header.kind {
+                MessageKind::Close => return Ok(false),
+                MessageKind::GetRegDevices => {
+                    let peers = self.peers.keys().cloned().collect::<Vec<ID>>();
+                    self.to_handle(MessageKind::Reply, serde_json::to_string(&peers)?)
+                        .await?;
+                }
+                MessageKind::GetPeers => {
+                    let peers = self
+                        .peers
+                        .keys()
+                        .map(|id| Peer {
+                            id: id.clone(),
+                            name: self
+                                .names
+                                .get(id)
+                                .cloned()
+                                .unwrap_or_else(|| id.to_string()),
+                        })
+                        .collect::<Vec<_>>();
+                    self.to_handle(MessageKind::Reply, serde_json::to_string(&peers)?)
+                        .await?;
+                }
+                MessageKind::JoinGroup => {
+                    let group = msg.body.trim().to_string();
+                    if group.is_empty() {
+                        self.refuse(ErrorReply::BadRequest("Missing group".to_string()))
+                            .await?;
+                        return Ok(true);
+                    }
+
+                    log::info!("Moving to LAN group '{}'", group);
+                    self.config.group = group;
+                    // Dropping their senders closes the connections
+                    for id in self.peers.drain().map(|(id, _)| id).collect::<Vec<_>>() {
+                        self.to_handle(MessageKind::ClosedRegDevice, serde_json::to_string(&id)?)
+                            .await?;
+                    }
+                    self.names.clear();
+                    self.dialing.clear();
+                    self.to_handle(MessageKind::Reply, "[]".to_string()).await?;
+                }
+                kind => {
+                    self.refuse(ErrorReply::BadRequest(format!(
+                        "{:?} is not supported in LAN mode",
+                        kind
+                    )))
+                    .await?
+                }
+            },
+
+            target => match self.peers.get(&target) {
+                Some(peer) => {
+                    if peer.send(msg).await.is_err() {
+                        log::warn!("LAN peer {:?} is gone", target);
+                    }
+                }
+                None => {
+                    let e = ErrorReply::Forbidden(format!("{:?} is not reachable", target));
+                    self.refuse(e).await?
+                }
+            },
+        }
+
+        Ok(true)
+    }
+
+    /// Delivers a message to the handle as if the Master sent it.
+    async fn to_handle(&self, kind: MessageKind, body: String) -> anyhow::Result<()> {
+        let msg = Message {
+            header: Header {
+                kind,
+                target: self.id.clone(),
+                ttl_secs: None,
+            },
+            body,
+            tail: Tail { from: ID::Master },
+        };
+        self.tx.send(msg).await?;
+        Ok(())
+    }
+
+    async fn refuse(&self, e: ErrorReply) -> anyhow::Result<()> {
+        log::warn!("Refused message in LAN mode due to {}", e);
+        self.tx.send(e.to_message(self.id.clone())?).await?;
+        Ok(())
+    }
+}
+
+/// Binds the announcement port, shared with other clients on the same host.
+fn discovery_socket(port: u16) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn hello_message(hello: &LanAnnounce, target: ID) -> serde_json::Result<Message> {
+    Ok(Message {
+        header: Header {
+            kind: MessageKind::Register,
+            target,
+            ttl_secs: None,
+        },
+        body: serde_json::to_string(hello)?,
+        tail: Tail {
+            from: hello.id.clone(),
+        },
+    })
+}
+
+async fn recv_hello(stream: &mut MessageStream, group: &str) -> anyhow::Result<LanAnnounce> {
+    let msg = stream.recv().await?;
+    anyhow::ensure!(
+        msg.header.kind == MessageKind::Register,
+        "Expected Register, got {:?}",
+        msg.header.kind
+    );
+
+    let hello: LanAnnounce = serde_json::from_str(&msg.body)?;
+    anyhow::ensure!(hello.group == group, "Peer is in group '{}'", hello.group);
+    anyhow::ensure!(msg.tail.from == hello.id, "Peer sent a wrong ID");
+    Ok(hello)
+}
+
+async fn dial(
+    addr: SocketAddr,
+    hello: LanAnnounce,
+    expected: LanAnnounce,
+) -> anyhow::Result<MessageStream> {
+    let mut stream = MessageStream::connect(addr).await?;
+    stream
+        .send(hello_message(&hello, expected.id.clone())?)
+        .await?;
+
+    let theirs = recv_hello(&mut stream, &hello.group).await?;
+    anyhow::ensure!(theirs.id == expected.id, "Found {:?} instead", theirs.id);
+    Ok(stream)
+}
+
+async fn accept(
+    mut stream: MessageStream,
+    hello: LanAnnounce,
+) -> anyhow::Result<(LanAnnounce, MessageStream)> {
+    let theirs = recv_hello(&mut stream, &hello.group).await?;
+    anyhow::ensure!(theirs.id != hello.id, "Refused connection to itself");
+    stream
+        .send(hello_message(&hello, theirs.id.clone())?)
+        .await?;
+    Ok((theirs, stream))
+}
+
+/// Moves messages between a peer and the node, until either side is gone.
+async fn peer(
+    id: ID,
+    mut stream: MessageStream,
+    mut out: mpsc::Receiver<Message>,
+    events: mpsc::Sender<Event>,
+) {
+    loop {
+        tokio::select! {
+            res = stream.recv() => match res {
+                Ok(msg) => {
+                    if events.send(Event::Received(id.clone(), msg)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    log::debug!("Connection to LAN peer {:?} closed due to {}", id, e);
+                    break;
+                }
+            },
+
+            res = out.recv() => match res {
+                Some(msg) => {
+                    if let Err(e) = stream.send(msg).await {
+                        log::debug!("Connection to LAN peer {:?} failed due to {}", id, e);
+                        break;
+                    }
+                }
+                None => break,
+            },
+        }
+    }
+
+    let _ = events.send(Event::Closed(id)).await;
+}
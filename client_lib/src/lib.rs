@@ -1,38 +1,125 @@
+pub mod direct;
+pub mod e2e;
+pub mod lan;
+pub mod masters;
+
 use cross_messages::*;
+use direct::{Direct, Routed};
+use std::collections::VecDeque;
 use std::io::ErrorKind;
 use tokio::{net::ToSocketAddrs, sync::mpsc};
 
 pub struct CrossClient {
     master_stream: MessageStream,
+    fingerprint: Option<String>,
+    direct_min_bytes: Option<usize>,
 }
 
 impl CrossClient {
     pub async fn new(addr: impl ToSocketAddrs) -> std::io::Result<Self> {
-        Ok(CrossClient {
-            master_stream: MessageStream::connect(addr).await?,
-        })
+        Ok(CrossClient::with_stream(
+            MessageStream::connect(addr).await?,
+        ))
+    }
+
+    /// Connects to the Master through any transport, e.g. `ws::WebSocket`.
+    pub async fn connect<X: Transport>(transport: &X, addr: &str) -> std::io::Result<Self> {
+        Ok(CrossClient::with_stream(transport.connect(addr).await?))
+    }
+
+    fn with_stream(master_stream: MessageStream) -> Self {
+        CrossClient {
+            master_stream,
+            fingerprint: None,
+            direct_min_bytes: None,
+        }
+    }
+
+    /// Refuses to register at a Master reporting a different fingerprint.
+    /// The Master reports it itself, so this only catches connecting to
+    /// the wrong Master by mistake. Only `Quic::pinned` authenticates it.
+    pub fn pin_fingerprint(mut self, fingerprint: Option<String>) -> Self {
+        self.fingerprint = fingerprint;
+        self
+    }
+
+    /// Sends messages with at least `min_bytes` of body over direct
+    /// connections to other devices, where one can be set up.
+    pub fn direct_connections(mut self, min_bytes: Option<usize>) -> Self {
+        self.direct_min_bytes = min_bytes;
+        self
     }
 
-    pub async fn register(mut self) -> anyhow::Result<(RegisteredClient, CrossHandle)> {
-        log::info!("Attempting to Register to Master Server");
-        let reg_msg = Message::register();
+    /// Redeems a pairing code without registering.
+    pub async fn pair(mut self, redeem: PairRedeem) -> anyhow::Result<PairingGrant> {
+        log::info!("Attempting to Pair with Master Server");
+        let msg = Message {
+            header: Header {
+                kind: MessageKind::Pair,
+                target: ID::Master,
+                ttl_secs: None,
+            },
+            body: serde_json::to_string(&redeem)?,
+            tail: Tail {
+                from: ID::Unregistered,
+            },
+        };
+        self.master_stream.send(msg).await?;
+
+        let repl = self.master_stream.recv().await?;
+        if repl.header.kind == MessageKind::Error {
+            return Err(ErrorReply::from_message(&repl)?.into());
+        }
+
+        Ok(serde_json::from_str(&repl.body)?)
+    }
+
+    pub async fn register(
+        mut self,
+        request: RegisterRequest,
+    ) -> anyhow::Result<(RegisteredClient, CrossHandle)> {
+        log::info!(
+            "Attempting to Register to Master Server in group '{}'",
+            request.group
+        );
+        let reg_msg = Message::register(&request)?;
         self.master_stream.send(reg_msg).await?;
         let repl = self.master_stream.recv().await?;
-        let registered_id = ID::from_register_reply(repl)?;
+        let RegisterReply {
+            id: registered_id,
+            fingerprint,
+        } = RegisterReply::from_message(&repl)?;
+
+        if self.fingerprint.is_some() && self.fingerprint != fingerprint {
+            return Err(anyhow::anyhow!(
+                "Master fingerprint {:?} doesn't match the pinned {:?}",
+                fingerprint,
+                self.fingerprint
+            ));
+        }
 
         log::info!("Registered with {:?}", registered_id);
 
         let (reg_tx, rx) = mpsc::channel::<Message>(16);
         let (tx, reg_rx) = mpsc::channel::<Message>(16);
 
+        let direct = match self.direct_min_bytes {
+            Some(min_bytes) => {
+                Some(Direct::new(registered_id.clone(), &self.master_stream, min_bytes).await?)
+            }
+            None => None,
+        };
+
         let client_handle = CrossHandle {
             tx,
             rx,
+            pending: VecDeque::new(),
             registered_id,
         };
 
         let reg_client = RegisteredClient {
             master_stream: self.master_stream,
+            direct,
             tx: reg_tx,
             rx: reg_rx,
         };
@@ -44,12 +131,29 @@ impl CrossClient {
 pub struct CrossHandle {
     tx: mpsc::Sender<Message>,
     rx: mpsc::Receiver<Message>,
+    /// Received while `request` waited for a reply, handed out by `recv` first.
+    pending: VecDeque<Message>,
     pub registered_id: ID,
 }
 
 impl CrossHandle {
     pub async fn send(&self, to: ID, kind: MessageKind, body: String) -> anyhow::Result<()> {
-        let header = Header { kind, target: to };
+        self.send_with_ttl(to, kind, body, None).await
+    }
+
+    /// Sends content the receiver drops after `ttl_secs`, see `Header::ttl_secs`.
+    pub async fn send_with_ttl(
+        &self,
+        to: ID,
+        kind: MessageKind,
+        body: String,
+        ttl_secs: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let header = Header {
+            kind,
+            target: to,
+            ttl_secs,
+        };
         let tail = Tail {
             from: self.registered_id.clone(),
         };
@@ -62,7 +166,11 @@ impl CrossHandle {
     }
 
     pub fn blocking_send(&self, to: ID, kind: MessageKind, body: String) -> anyhow::Result<()> {
-        let header = Header { kind, target: to };
+        let header = Header {
+            kind,
+            target: to,
+            ttl_secs: None,
+        };
         let tail = Tail {
             from: self.registered_id.clone(),
         };
@@ -75,12 +183,71 @@ impl CrossHandle {
     }
 
     pub async fn recv(&mut self) -> Option<Message> {
-        self.rx.recv().await
+        match self.pending.pop_front() {
+            Some(msg) => Some(msg),
+            None => self.rx.recv().await,
+        }
+    }
+
+    /// Sends a request to the Master and waits for its `Reply`.
+    /// Other messages received in the meantime are kept for `recv`.
+    pub async fn request(&mut self, kind: MessageKind, body: String) -> anyhow::Result<Message> {
+        self.send(ID::Master, kind, body).await?;
+
+        while let Some(msg) = self.rx.recv().await {
+            let from_master = msg.tail.from == ID::Master;
+            match msg.header.kind {
+                MessageKind::Reply if from_master => return Ok(msg),
+                MessageKind::Error if from_master => {
+                    return Err(ErrorReply::from_message(&msg)?.into())
+                }
+                MessageKind::Close | MessageKind::Shutdown => {
+                    self.pending.push_back(msg);
+                    break;
+                }
+                _ => self.pending.push_back(msg),
+            }
+        }
+
+        Err(anyhow::anyhow!("Connection closed before reply"))
+    }
+
+    /// The other devices of the group with their names.
+    pub async fn peers(&mut self) -> anyhow::Result<Vec<Peer>> {
+        let reply = self.request(MessageKind::GetPeers, String::new()).await?;
+        Ok(serde_json::from_str(&reply.body)?)
+    }
+
+    /// Adds an entry to the shared history of the group, without waiting
+    /// for the Master to confirm it.
+    pub async fn push_history(&self, entry: &HistoryPush) -> anyhow::Result<()> {
+        self.send(
+            ID::Master,
+            MessageKind::HistoryPush,
+            serde_json::to_string(entry)?,
+        )
+        .await
+    }
+
+    /// The latest entries of the shared history, newest first.
+    pub async fn history(&mut self, query: &HistoryQuery) -> anyhow::Result<Vec<HistoryEntry>> {
+        let reply = self
+            .request(MessageKind::HistoryList, serde_json::to_string(query)?)
+            .await?;
+        Ok(serde_json::from_str(&reply.body)?)
+    }
+
+    pub async fn history_entry(&mut self, id: i64) -> anyhow::Result<HistoryEntry> {
+        let reply = self
+            .request(MessageKind::HistoryGet, id.to_string())
+            .await?;
+        Ok(serde_json::from_str(&reply.body)?)
     }
 }
 
 pub struct RegisteredClient {
     master_stream: MessageStream,
+    direct: Option<Direct>,
     tx: mpsc::Sender<Message>,
     rx: mpsc::Receiver<Message>,
 }
@@ -97,18 +264,76 @@ impl RegisteredClient {
                     };
                     log::info!("New from Master {:#?}", msg);
 
-                    if msg.header.kind == MessageKind::Close {
+                    if matches!(msg.header.kind, MessageKind::Close | MessageKind::Shutdown) {
                         self.tx.send(msg).await?;
                         return Ok(());
                     }
 
+                    if let Some(direct) = &mut self.direct {
+                        match msg.header.kind {
+                            MessageKind::Rendezvous if msg.tail.from == ID::Master => {
+                                direct.close_all();
+                                continue;
+                            }
+                            MessageKind::Rendezvous => {
+                                match direct.on_offer(&msg) {
+                                    Ok(Some(offer)) => { self.master_stream.send(offer).await?; }
+                                    Ok(None) => {}
+                                    Err(e) => log::warn!("Ignored direct connection offer due to {}", e),
+                                }
+                                continue;
+                            }
+                            MessageKind::ClosedRegDevice => {
+                                if let Ok(id) = serde_json::from_str::<ID>(&msg.body) {
+                                    direct.close(&id);
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+
                     self.tx.send(msg).await?;
                 },
 
+                res = next_direct(&mut self.direct) => {
+                    let Some(direct) = &mut self.direct else { continue };
+                    match direct.handle(res?).await {
+                        Some(Routed::Deliver(msg)) => self.tx.send(msg).await?,
+                        Some(Routed::Relay(msg)) => { self.master_stream.send(msg).await?; }
+                        None => {}
+                    }
+                }
+
                 res = self.rx.recv() => {
-                    self.master_stream.send(res.unwrap()).await?;
+                    // The CrossHandle was dropped
+                    let Some(msg) = res else { return Ok(()) };
+
+                    let msg = match &mut self.direct {
+                        Some(direct) => {
+                            // Peers of the old group may not be reachable from the new one
+                            if msg.header.kind == MessageKind::JoinGroup {
+                                direct.close_all();
+                            }
+                            if let Some(offer) = direct.offer_for(&msg)? {
+                                self.master_stream.send(offer).await?;
+                            }
+                            match direct.send(msg).await {
+                                Some(msg) => msg,
+                                None => continue,
+                            }
+                        }
+                        None => msg,
+                    };
+                    self.master_stream.send(msg).await?;
                 }
             }
         }
     }
 }
+
+async fn next_direct(direct: &mut Option<Direct>) -> std::io::Result<direct::Event> {
+    match direct {
+        Some(direct) => direct.next().await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,65 @@
+use cross_messages::Transport;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+/// Time given to a single Master to accept the connection.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolves every `host:port` in order, keeping all addresses of a host.
+/// Hosts which fail to resolve are skipped.
+pub async fn resolve(masters: &[String]) -> Vec<SocketAddr> {
+    let mut resolved = Vec::new();
+    for master in masters {
+        match tokio::net::lookup_host(master.as_str()).await {
+            Ok(addrs) => {
+                for addr in addrs {
+                    if !resolved.contains(&addr) {
+                        resolved.push(addr);
+                    }
+                }
+            }
+            Err(e) => log::warn!("Failed to resolve Master '{}' due to {}", master, e),
+        }
+    }
+    resolved
+}
+
+/// Connects to all `addrs` at once through `transport` and orders them
+/// fastest to accept first. Those which didn't accept in time come last,
+/// the failure may have been transient.
+pub async fn by_latency<X>(addrs: Vec<SocketAddr>, transport: X) -> Vec<SocketAddr>
+where
+    X: Transport + Clone + 'static,
+{
+    let mut probes = JoinSet::new();
+    for addr in addrs.iter().copied() {
+        let transport = transport.clone();
+        probes.spawn(async move {
+            let start = Instant::now();
+            tokio::time::timeout(CONNECT_TIMEOUT, transport.connect(&addr.to_string()))
+                .await
+                .ok()?
+                .ok()?;
+            Some((addr, start.elapsed()))
+        });
+    }
+
+    let mut reachable = Vec::new();
+    while let Some(res) = probes.join_next().await {
+        if let Ok(Some((addr, latency))) = res {
+            log::debug!("Master {} accepted after {:?}", addr, latency);
+            reachable.push(addr);
+        }
+    }
+
+    let unreachable = addrs
+        .into_iter()
+        .filter(|addr| !reachable.contains(addr))
+        .collect::<Vec<_>>();
+    if !unreachable.is_empty() {
+        log::debug!("Masters {:?} didn't accept, trying them last", unreachable);
+    }
+    reachable.extend(unreachable);
+    reachable
+}
@@ -1,5 +1,9 @@
+pub mod quic;
 pub mod tcp;
+pub mod transport;
+pub mod ws;
 pub use tcp::*;
+pub use transport::{Listener, Transport};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -11,28 +15,96 @@ pub struct Message {
     pub tail: Tail,
 }
 
+pub const DEFAULT_GROUP: &str = "default";
+
 impl Message {
-    pub fn register() -> Self {
-        Message {
+    pub fn register(request: &RegisterRequest) -> serde_json::Result<Self> {
+        Ok(Message {
             header: Header {
                 kind: MessageKind::Register,
                 target: ID::Master,
+                ttl_secs: None,
             },
-            body: String::new(),
+            body: serde_json::to_string(request)?,
             tail: Tail {
                 from: ID::Unregistered,
             },
+        })
+    }
+}
+
+/// Body of a `Register` message.
+/// Devices only see and reach other devices of the same group and user.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RegisterRequest {
+    #[serde(default = "default_group")]
+    pub group: String,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub device: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl RegisterRequest {
+    pub fn from_message(msg: &Message) -> serde_json::Result<Self> {
+        if msg.body.is_empty() {
+            return Ok(RegisterRequest::default());
+        }
+
+        serde_json::from_str(&msg.body)
+    }
+}
+
+impl Default for RegisterRequest {
+    fn default() -> Self {
+        RegisterRequest {
+            group: default_group(),
+            user: None,
+            password: None,
+            device: None,
+            token: None,
         }
     }
 }
 
+/// Body of the `Reply` to a `Register` message.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RegisterReply {
+    pub id: ID,
+    /// As reported by the Master, only fit to detect a wrong Master.
+    /// QUIC clients verify it on connect, see `quic::Quic::pinned`.
+    pub fingerprint: Option<String>,
+}
+
+impl RegisterReply {
+    pub fn from_message(msg: &Message) -> anyhow::Result<Self> {
+        if msg.header.kind == MessageKind::Error {
+            return Err(ErrorReply::from_message(msg)?.into());
+        }
+
+        Ok(serde_json::from_str(&msg.body)?)
+    }
+}
+
+fn default_group() -> String {
+    DEFAULT_GROUP.to_string()
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Header {
     pub kind: MessageKind,
     pub target: ID,
+    /// Seconds the receiver keeps the content, e.g. on its clipboard.
+    /// Such content is never added to a history.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_secs: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum ID {
     Master,
     Unregistered,
@@ -44,8 +116,30 @@ impl ID {
         ID::Slave(Uuid::new_v4())
     }
 
-    pub fn from_register_reply(msg: Message) -> serde_json::Result<Self> {
-        Ok(serde_json::from_str(&msg.body)?)
+    pub fn from_register_reply(msg: Message) -> anyhow::Result<Self> {
+        Ok(RegisterReply::from_message(&msg)?.id)
+    }
+}
+
+impl std::fmt::Display for ID {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ID::Master => write!(f, "master"),
+            ID::Unregistered => write!(f, "unregistered"),
+            ID::Slave(uuid) => write!(f, "{}", uuid),
+        }
+    }
+}
+
+impl std::str::FromStr for ID {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "master" => Ok(ID::Master),
+            "unregistered" => Ok(ID::Unregistered),
+            _ => Ok(ID::Slave(s.parse()?)),
+        }
     }
 }
 
@@ -55,16 +149,239 @@ pub enum MessageKind {
     Register,
     Close,
     Reply,
+    Error,
     GetRegDevices,
+    GetPeers,
+    Admin,
+    PairingCode,
+    Pair,
+    JoinGroup,
+    Federate,
+    HistoryPush,
+    HistoryList,
+    HistoryGet,
     // ----------------------
     // Bounced to the target
     // ----------------------
     Clipboard,
     NewRegDevice,
     ClosedRegDevice,
+    Notice,
+    Shutdown,
+    /// Offers a direct connection to the target. Sent by the Master itself,
+    /// it closes all direct connections of the target.
+    Rendezvous,
+    /// Asks the target for its clipboard, answered with `Clipboard` or a
+    /// `Notice` on refusal.
+    PullClipboard,
+    // ----------------------
+    // Application defined, handled by Master middleware or bounced
+    // ----------------------
+    Custom(u16),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Tail {
     pub from: ID,
 }
+
+/// Body of an `Error` message sent by the Master.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ErrorReply {
+    Unauthorized(String),
+    Forbidden(String),
+    BadRequest(String),
+    RateLimited(String),
+    TooLarge(String),
+    NotFound(String),
+    /// The Master failed, e.g. its storage, not the request.
+    Internal(String),
+}
+
+impl ErrorReply {
+    pub fn to_message(&self, target: ID) -> serde_json::Result<Message> {
+        Ok(Message {
+            header: Header {
+                kind: MessageKind::Error,
+                target,
+                ttl_secs: None,
+            },
+            body: serde_json::to_string(self)?,
+            tail: Tail { from: ID::Master },
+        })
+    }
+
+    pub fn from_message(msg: &Message) -> serde_json::Result<Self> {
+        serde_json::from_str(&msg.body)
+    }
+}
+
+impl std::fmt::Display for ErrorReply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorReply::Unauthorized(e) => write!(f, "Unauthorized: {}", e),
+            ErrorReply::Forbidden(e) => write!(f, "Forbidden: {}", e),
+            ErrorReply::BadRequest(e) => write!(f, "Bad request: {}", e),
+            ErrorReply::RateLimited(e) => write!(f, "Rate limited: {}", e),
+            ErrorReply::TooLarge(e) => write!(f, "Too large: {}", e),
+            ErrorReply::NotFound(e) => write!(f, "Not found: {}", e),
+            ErrorReply::Internal(e) => write!(f, "Internal error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ErrorReply {}
+
+/// Body of an `Admin` message, only accepted from admin users.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum AdminRequest {
+    ListUsers,
+    AddUser {
+        name: String,
+        password: String,
+        admin: bool,
+    },
+    RemoveUser {
+        name: String,
+    },
+    ListDevices {
+        user: String,
+    },
+    RevokeDevice {
+        user: String,
+        device: String,
+    },
+    RenameDevice {
+        user: String,
+        device: String,
+        new_name: String,
+    },
+}
+
+/// A device of the same group, as listed in the reply to `GetPeers`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Peer {
+    pub id: ID,
+    pub name: String,
+}
+
+/// Body of the `Shutdown` message the Master sends before it goes down.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ShutdownNotice {
+    pub reason: Option<String>,
+    /// Seconds after which the Master is expected to be back.
+    pub reconnect_after_secs: Option<u64>,
+}
+
+impl ShutdownNotice {
+    pub fn to_message(&self, target: ID) -> serde_json::Result<Message> {
+        Ok(Message {
+            header: Header {
+                kind: MessageKind::Shutdown,
+                target,
+                ttl_secs: None,
+            },
+            body: serde_json::to_string(self)?,
+            tail: Tail { from: ID::Master },
+        })
+    }
+
+    pub fn from_message(msg: &Message) -> serde_json::Result<Self> {
+        serde_json::from_str(&msg.body)
+    }
+}
+
+/// Body of the `Reply` to a `PairingCode` message.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PairingCode {
+    pub code: String,
+    pub expires_in_secs: u64,
+}
+
+/// Body of a `Pair` message, sent by an unregistered device.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PairRedeem {
+    pub code: String,
+    pub device: String,
+}
+
+/// Body of the `Reply` to a `Pair` message.
+/// Holds everything the new device needs to register on its own.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PairingGrant {
+    pub group: String,
+    pub user: Option<String>,
+    pub device: String,
+    pub token: Option<String>,
+    pub fingerprint: Option<String>,
+}
+
+/// Body of the `Federate` message both Masters send first on a federation link.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FederationHello {
+    pub name: String,
+    pub token: String,
+}
+
+/// Body of a `NewRegDevice` sent over a federation link to the `Master`,
+/// announcing a device connected to the sending Master.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FederatedDevice {
+    pub id: ID,
+    pub name: String,
+    pub group: String,
+    pub user: Option<String>,
+}
+
+/// Broadcast by clients in LAN mode to find each other, and sent as the
+/// body of the first message on a connection between two of them.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LanAnnounce {
+    pub id: ID,
+    pub group: String,
+    pub device: Option<String>,
+    /// Port the client accepts connections from other clients on.
+    pub port: u16,
+}
+
+/// Body of a `Rendezvous` message, offering a direct connection to its target.
+/// The Master adds the address it sees the sender at to the candidates.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RendezvousOffer {
+    /// Port the sender accepts direct connections on.
+    pub port: u16,
+    pub candidates: Vec<std::net::SocketAddr>,
+    /// Has to be presented when connecting to the sender.
+    pub secret: String,
+}
+
+/// Body of a `HistoryPush`, adding to the shared history of the sender's group.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HistoryPush {
+    pub content_type: String,
+    pub content: String,
+    /// Sealed by the devices, the Master only stores it.
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/// Body of a `HistoryList`, answered with the latest matching `HistoryEntry`s.
+/// A `HistoryGet` carries the id of an entry instead.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HistoryQuery {
+    /// Only entries copied on the device of this name.
+    #[serde(default)]
+    pub device: Option<String>,
+    pub limit: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub id: i64,
+    /// Seconds since the Unix epoch.
+    pub created: i64,
+    pub device: String,
+    pub content_type: String,
+    pub content: String,
+    pub encrypted: bool,
+}
@@ -0,0 +1,445 @@
+//! QUIC transport. Connections survive the client changing its address,
+//! e.g. a laptop switching networks. Frames are sent in order on one stream
+//! opened by the client.
+//!
+//! Traffic is encrypted with the certificate of the Master, see
+//! `Quic::with_certificate`. Clients pinning a fingerprint refuse any other
+//! certificate, without one they accept any, e.g. to pair.
+
+use async_trait::async_trait;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+
+use crate::tcp::{MessageStream, DEFAULT_MAX_FRAME_LEN};
+use crate::transport::{Connection, Listener, Transport};
+
+const ALPN: &[u8] = b"crosslive";
+const SERVER_NAME: &str = "crosslive";
+
+/// Time given to a new connection to complete the handshake and open its stream.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keeps idle connections open through NATs and notices lost peers.
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// Time without any packet after which the connection is given up,
+/// long enough to switch networks in between.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Time given to the peer to read everything sent before closing.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// PKCS#8 prefix of an Ed25519 key, followed by its 32 byte seed.
+const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+/// Listens at and connects to `host:port` over UDP.
+#[derive(Clone, Default)]
+pub struct Quic {
+    /// Served by `bind`, a new one is created on every bind if unset.
+    certificate: Option<Certificate>,
+    /// Fingerprint `connect` expects the certificate of the Master to have.
+    pinned: Option<String>,
+}
+
+/// Certificate of a Master with its PKCS#8 key, both DER encoded.
+#[derive(Clone)]
+pub struct Certificate {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+pub struct QuicListener {
+    incoming: Mutex<mpsc::Receiver<io::Result<(MessageStream, SocketAddr)>>>,
+    acceptor: JoinHandle<()>,
+    local_addr: SocketAddr,
+}
+
+struct QuicConnection {
+    conn: quinn::Connection,
+    /// Taken when dropped, to finish it in the background.
+    ordered: Option<quinn::SendStream>,
+    frames: mpsc::Receiver<Vec<u8>>,
+    reader: JoinHandle<()>,
+    /// Clients own their endpoint, which has to outlive the connection.
+    endpoint: Option<quinn::Endpoint>,
+}
+
+impl Quic {
+    /// Serves `certificate`, so devices can pin its fingerprint.
+    pub fn with_certificate(certificate: Certificate) -> Self {
+        Quic {
+            certificate: Some(certificate),
+            pinned: None,
+        }
+    }
+
+    /// Refuses Masters whose certificate doesn't have `fingerprint`.
+    pub fn pinned(fingerprint: Option<String>) -> Self {
+        Quic {
+            certificate: None,
+            pinned: fingerprint,
+        }
+    }
+}
+
+impl Certificate {
+    /// Creates a self-signed certificate for the Ed25519 key with the 32 byte `seed`.
+    pub fn from_seed(seed: &[u8]) -> anyhow::Result<Self> {
+        if seed.len() != 32 {
+            return Err(anyhow::anyhow!(
+                "Expected a key of 32 bytes, got {}",
+                seed.len()
+            ));
+        }
+
+        let mut key = ED25519_PKCS8_PREFIX.to_vec();
+        key.extend_from_slice(seed);
+        let key_pair = rcgen::KeyPair::try_from(&PrivatePkcs8KeyDer::from(key.as_slice()))?;
+        let cert =
+            rcgen::CertificateParams::new(vec![SERVER_NAME.to_string()])?.self_signed(&key_pair)?;
+        Ok(Certificate {
+            cert: cert.der().to_vec(),
+            key,
+        })
+    }
+
+    fn generate() -> anyhow::Result<Self> {
+        let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+        Ok(Certificate {
+            cert: certified.cert.der().to_vec(),
+            key: certified.key_pair.serialize_der(),
+        })
+    }
+
+    /// SHA-256 of the certificate, as devices pin it.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.cert)
+    }
+}
+
+fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .chunks(2)
+        .map(|pair| pair.concat())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[async_trait]
+impl Transport for Quic {
+    type Listener = QuicListener;
+
+    async fn bind(&self, addr: &str) -> io::Result<QuicListener> {
+        let addr = resolve(addr).await?;
+        let certificate = match &self.certificate {
+            Some(certificate) => certificate.clone(),
+            None => Certificate::generate().map_err(io::Error::other)?,
+        };
+        let config = server_config(certificate).map_err(io::Error::other)?;
+        let endpoint = quinn::Endpoint::server(config, addr)?;
+        let (tx, rx) = mpsc::channel(16);
+
+        Ok(QuicListener {
+            incoming: Mutex::new(rx),
+            local_addr: endpoint.local_addr()?,
+            acceptor: tokio::spawn(accept_all(endpoint, tx)),
+        })
+    }
+
+    async fn connect(&self, addr: &str) -> io::Result<MessageStream> {
+        let addr = resolve(addr).await?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let endpoint = quinn::Endpoint::client(local)?;
+        let conn = endpoint
+            .connect_with(
+                client_config(self.pinned.clone()).map_err(io::Error::other)?,
+                addr,
+                SERVER_NAME,
+            )
+            .map_err(io::Error::other)?
+            .await?;
+        let (send, recv) = conn.open_bi().await?;
+
+        let connection = QuicConnection::new(conn, send, recv, Some(endpoint));
+        Ok(MessageStream::over(connection, None))
+    }
+}
+
+#[async_trait]
+impl Listener for QuicListener {
+    async fn accept(&self) -> io::Result<(MessageStream, SocketAddr)> {
+        self.incoming.lock().await.recv().await.unwrap_or_else(|| {
+            Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "QUIC listener stopped",
+            ))
+        })
+    }
+}
+
+impl QuicListener {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for QuicListener {
+    fn drop(&mut self) {
+        self.acceptor.abort();
+    }
+}
+
+async fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::AddrNotAvailable,
+            format!("'{}' resolved to no address", addr),
+        )
+    })
+}
+
+/// Does the handshakes apart from `accept`, so a slow client holds up no other.
+async fn accept_all(
+    endpoint: quinn::Endpoint,
+    tx: mpsc::Sender<io::Result<(MessageStream, SocketAddr)>>,
+) {
+    while let Some(incoming) = endpoint.accept().await {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let addr = incoming.remote_address();
+            let handshake = async {
+                let conn = incoming.await?;
+                let (send, recv) = conn.accept_bi().await?;
+                Ok::<_, quinn::ConnectionError>(QuicConnection::new(conn, send, recv, None))
+            };
+
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok(connection)) => {
+                    let stream = MessageStream::over(connection, None);
+                    let _ = tx.send(Ok((stream, addr))).await;
+                }
+                Ok(Err(e)) => log::debug!("QUIC handshake with {} failed due to {}", addr, e),
+                Err(_) => log::debug!("QUIC handshake with {} timed out", addr),
+            }
+        });
+    }
+}
+
+impl QuicConnection {
+    fn new(
+        conn: quinn::Connection,
+        send: quinn::SendStream,
+        recv: quinn::RecvStream,
+        endpoint: Option<quinn::Endpoint>,
+    ) -> Self {
+        let (tx, frames) = mpsc::channel(16);
+        QuicConnection {
+            reader: tokio::spawn(read_ordered(conn.clone(), recv, tx)),
+            conn,
+            ordered: Some(send),
+            frames,
+            endpoint,
+        }
+    }
+}
+
+#[async_trait]
+impl Connection for QuicConnection {
+    /// Called with whole frames by `MessageStream`.
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let Some(ordered) = &mut self.ordered else {
+            return Err(io::Error::from(ErrorKind::NotConnected));
+        };
+        ordered.write_all(buf).await.map_err(io::Error::from)
+    }
+
+    async fn read_buf(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        match self.frames.recv().await {
+            Some(frame) => {
+                buf.extend_from_slice(&frame);
+                Ok(frame.len())
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+impl Drop for QuicConnection {
+    fn drop(&mut self) {
+        self.reader.abort();
+
+        let conn = self.conn.clone();
+        let ordered = self.ordered.take();
+        let endpoint = self.endpoint.take();
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            conn.close(0u32.into(), b"");
+            return;
+        };
+        runtime.spawn(async move {
+            let sent = async {
+                if let Some(mut ordered) = ordered {
+                    if ordered.finish().is_ok() {
+                        let _ = ordered.stopped().await;
+                    }
+                }
+            };
+            let _ = tokio::time::timeout(CLOSE_TIMEOUT, sent).await;
+            conn.close(0u32.into(), b"");
+            if let Some(endpoint) = endpoint {
+                endpoint.wait_idle().await;
+            }
+        });
+    }
+}
+
+/// Reads the frames of the ordered stream one by one.
+async fn read_ordered(
+    conn: quinn::Connection,
+    mut recv: quinn::RecvStream,
+    tx: mpsc::Sender<Vec<u8>>,
+) {
+    loop {
+        let mut prefix = [0u8; 4];
+        if recv.read_exact(&mut prefix).await.is_err() {
+            return;
+        }
+
+        let len = u32::from_be_bytes(prefix) as usize;
+        if len > DEFAULT_MAX_FRAME_LEN {
+            log::debug!("Closing connection sending a frame of {} bytes", len);
+            conn.close(1u32.into(), b"frame too large");
+            return;
+        }
+
+        let mut frame = vec![0u8; 4 + len];
+        frame[..4].copy_from_slice(&prefix);
+        if recv.read_exact(&mut frame[4..]).await.is_err() || tx.send(frame).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn transport_config() -> quinn::TransportConfig {
+    let mut config = quinn::TransportConfig::default();
+    config.keep_alive_interval(Some(KEEP_ALIVE));
+    config.max_idle_timeout(IDLE_TIMEOUT.try_into().ok());
+    config
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn server_config(certificate: Certificate) -> anyhow::Result<quinn::ServerConfig> {
+    let cert = CertificateDer::from(certificate.cert);
+    let key = PrivatePkcs8KeyDer::from(certificate.key);
+
+    let mut tls = rustls::ServerConfig::builder_with_provider(provider())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key.into())?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+    config.transport_config(Arc::new(transport_config()));
+    Ok(config)
+}
+
+fn client_config(pinned: Option<String>) -> anyhow::Result<quinn::ClientConfig> {
+    let provider = provider();
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertificate { provider, pinned }))
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+    config.transport_config(Arc::new(transport_config()));
+    Ok(config)
+}
+
+/// Accepts the certificate with the pinned fingerprint, or any while none is
+/// pinned. Either way the Master has to hold the key of the certificate.
+#[derive(Debug)]
+struct PinnedCertificate {
+    provider: Arc<CryptoProvider>,
+    pinned: Option<String>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let Some(pinned) = &self.pinned else {
+            return Ok(ServerCertVerified::assertion());
+        };
+
+        let fingerprint = fingerprint(end_entity);
+        if !fingerprint.eq_ignore_ascii_case(pinned) {
+            return Err(rustls::Error::General(format!(
+                "Master certificate has fingerprint {}, expected {}",
+                fingerprint, pinned
+            )));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -1,9 +1,10 @@
 use std::io::ErrorKind;
 
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 
+use crate::transport::Connection;
+
 use super::*;
 
 pub struct MessageListener {
@@ -23,32 +24,156 @@ impl MessageListener {
 
     pub async fn accept(&self) -> std::io::Result<(MessageStream, SocketAddr)> {
         let (inner, addr) = self.inner.accept().await?;
-        Ok((MessageStream { inner }, addr))
+        Ok((MessageStream::with(inner), addr))
+    }
+}
+
+/// Bytes read from the socket at once.
+const READ_CHUNK: usize = 8 * 1024;
+
+/// Upper bound for a single frame, larger frames are skipped.
+pub const DEFAULT_MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Returned by `MessageStream::recv` for a frame over the limit, which is skipped.
+/// The stream stays usable for the following frames.
+#[derive(Debug)]
+pub struct FrameTooLarge {
+    pub len: usize,
+    pub max: usize,
+}
+
+impl std::fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Frame of {} bytes exceeds limit of {}", self.len, self.max)
     }
 }
 
+impl std::error::Error for FrameTooLarge {}
+
+impl FrameTooLarge {
+    /// The skipped frame, if `e` was returned for one.
+    pub fn from_io(e: &std::io::Error) -> Option<&FrameTooLarge> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+/// Messages are sent as JSON, prefixed with their length as big endian `u32`.
 pub struct MessageStream {
-    inner: TcpStream,
+    inner: Box<dyn Connection>,
+    local_addr: Option<SocketAddr>,
+    max_frame_len: usize,
+    last_frame_len: usize,
+    /// Bytes received but not yet taken as a whole frame.
+    buffer: Vec<u8>,
+    /// Bytes of an oversized frame still to be dropped.
+    skip: usize,
 }
 
 impl MessageStream {
     pub async fn connect(addr: impl ToSocketAddrs) -> std::io::Result<Self> {
-        Ok(MessageStream {
-            inner: TcpStream::connect(addr).await?,
+        Ok(MessageStream::with(TcpStream::connect(addr).await?))
+    }
+
+    pub fn with(inner: TcpStream) -> Self {
+        let local_addr = inner.local_addr().ok();
+        MessageStream::over(inner, local_addr)
+    }
+
+    /// Frames messages on any other transport.
+    pub fn over(inner: impl Connection + 'static, local_addr: Option<SocketAddr>) -> Self {
+        MessageStream {
+            inner: Box::new(inner),
+            local_addr,
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            last_frame_len: 0,
+            buffer: Vec::new(),
+            skip: 0,
+        }
+    }
+
+    pub fn set_max_frame_len(&mut self, max_frame_len: usize) {
+        self.max_frame_len = max_frame_len;
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.local_addr.ok_or_else(|| {
+            std::io::Error::new(ErrorKind::Unsupported, "Transport has no socket address")
         })
     }
 
+    /// Bytes on the wire of the last received message, including its prefix.
+    pub fn last_frame_len(&self) -> usize {
+        self.last_frame_len
+    }
+
+    /// Returns the bytes on the wire, including the length prefix.
     pub async fn send(&mut self, msg: Message) -> std::io::Result<usize> {
-        self.inner.write(&serde_json::to_vec(&msg)?).await
+        let mut buffer = vec![0; 4];
+        serde_json::to_writer(&mut buffer, &msg)?;
+        let len = u32::try_from(buffer.len() - 4)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidInput, e))?;
+        buffer[..4].copy_from_slice(&len.to_be_bytes());
+
+        // A single write, so message based transports carry one frame each
+        self.inner.write_all(&buffer).await?;
+        Ok(buffer.len())
     }
 
+    /// Cancel safe, a partly received frame is kept for the next call.
+    /// This allows using it in `select!` next to other branches.
     pub async fn recv(&mut self) -> std::io::Result<Message> {
-        let mut buffer = [0; 2048];
-        let len = self.inner.read(&mut buffer).await?;
-        match serde_json::from_slice::<Message>(&buffer[..len]) {
+        loop {
+            if let Some(res) = self.next_frame() {
+                return res;
+            }
+
+            self.buffer.reserve(READ_CHUNK);
+            if self.inner.read_buf(&mut self.buffer).await? == 0 {
+                return Err(std::io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Connection closed",
+                ));
+            }
+        }
+    }
+
+    /// Takes the next whole frame out of the buffer, if there is one.
+    fn next_frame(&mut self) -> Option<std::io::Result<Message>> {
+        if self.skip > 0 {
+            let skipped = self.skip.min(self.buffer.len());
+            self.buffer.drain(..skipped);
+            self.skip -= skipped;
+            if self.skip > 0 {
+                return None;
+            }
+        }
+
+        let prefix: [u8; 4] = self.buffer.get(..4)?.try_into().ok()?;
+        let len = u32::from_be_bytes(prefix) as usize;
+
+        if len > self.max_frame_len {
+            self.buffer.drain(..4);
+            self.skip = len;
+            self.last_frame_len = len + 4;
+            return Some(Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                FrameTooLarge {
+                    len,
+                    max: self.max_frame_len,
+                },
+            )));
+        }
+
+        if self.buffer.len() < len + 4 {
+            return None;
+        }
+
+        self.last_frame_len = len + 4;
+        let frame = self.buffer.drain(..len + 4).skip(4).collect::<Vec<u8>>();
+        Some(match serde_json::from_slice::<Message>(&frame) {
             Ok(m) => Ok(m),
             Err(e) if e.is_eof() => Err(std::io::Error::new(ErrorKind::UnexpectedEof, e)),
             Err(e) => Err(std::io::Error::new(ErrorKind::InvalidInput, e)),
-        }
+        })
     }
 }
@@ -0,0 +1,269 @@
+//! Transports messages are framed on. `MessageStream` only needs a byte
+//! stream, so the Master and clients can talk over any of them.
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::quic::Quic;
+use crate::tcp::{MessageListener, MessageStream};
+use crate::ws::WebSocket;
+
+/// A byte stream messages can be framed on.
+#[async_trait]
+pub trait Connection: Send + Sync {
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// Appends the bytes received next to `buf`, `0` once closed.
+    /// Must be cancel safe, as `MessageStream::recv` is.
+    async fn read_buf(&mut self, buf: &mut Vec<u8>) -> io::Result<usize>;
+}
+
+#[async_trait]
+impl<S> Connection for S
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        AsyncWriteExt::write_all(self, buf).await
+    }
+
+    async fn read_buf(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        AsyncReadExt::read_buf(self, buf).await
+    }
+}
+
+/// Connects to and listens at addresses of one kind, e.g. `host:port` for TCP.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    type Listener: Listener + 'static;
+
+    async fn bind(&self, addr: &str) -> io::Result<Self::Listener>;
+
+    async fn connect(&self, addr: &str) -> io::Result<MessageStream>;
+}
+
+#[async_trait]
+pub trait Listener: Send + Sync {
+    /// Returns the remote address, which the Master tells connections apart by.
+    /// Transports without one number them with `Numbering` instead.
+    async fn accept(&self) -> io::Result<(MessageStream, SocketAddr)>;
+}
+
+#[async_trait]
+impl Listener for Box<dyn Listener> {
+    async fn accept(&self) -> io::Result<(MessageStream, SocketAddr)> {
+        (**self).accept().await
+    }
+}
+
+/// Counts connections as ports of the unspecified address, so they stay
+/// apart for transports without addresses of their own.
+#[derive(Default)]
+pub struct Numbering {
+    next: AtomicU16,
+}
+
+impl Numbering {
+    pub fn next(&self) -> SocketAddr {
+        let port = self.next.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)
+    }
+}
+
+/// Transports which can be picked in a config file. Devices find Masters by
+/// `host:port`, so `Unix` and `Memory` are only for embedding the Master and
+/// clients, see `MasterServer::bind` and `CrossClient::connect`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransportKind {
+    #[default]
+    Tcp,
+    WebSocket,
+    Quic,
+}
+
+#[async_trait]
+impl Transport for TransportKind {
+    type Listener = Box<dyn Listener>;
+
+    async fn bind(&self, addr: &str) -> io::Result<Box<dyn Listener>> {
+        TransportConfig::from(*self).bind(addr).await
+    }
+
+    async fn connect(&self, addr: &str) -> io::Result<MessageStream> {
+        TransportConfig::from(*self).connect(addr).await
+    }
+}
+
+/// A `TransportKind` with the certificate a QUIC Master serves or the
+/// fingerprint a QUIC client expects.
+#[derive(Clone, Default)]
+pub struct TransportConfig {
+    pub kind: TransportKind,
+    pub quic: Quic,
+}
+
+impl From<TransportKind> for TransportConfig {
+    fn from(kind: TransportKind) -> Self {
+        TransportConfig {
+            kind,
+            quic: Quic::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for TransportConfig {
+    type Listener = Box<dyn Listener>;
+
+    async fn bind(&self, addr: &str) -> io::Result<Box<dyn Listener>> {
+        Ok(match self.kind {
+            TransportKind::Tcp => Box::new(Tcp.bind(addr).await?),
+            TransportKind::WebSocket => Box::new(WebSocket.bind(addr).await?),
+            TransportKind::Quic => Box::new(self.quic.bind(addr).await?),
+        })
+    }
+
+    async fn connect(&self, addr: &str) -> io::Result<MessageStream> {
+        match self.kind {
+            TransportKind::Tcp => Tcp.connect(addr).await,
+            TransportKind::WebSocket => WebSocket.connect(addr).await,
+            TransportKind::Quic => self.quic.connect(addr).await,
+        }
+    }
+}
+
+/// Plain TCP, the default.
+pub struct Tcp;
+
+#[async_trait]
+impl Transport for Tcp {
+    type Listener = MessageListener;
+
+    async fn bind(&self, addr: &str) -> io::Result<MessageListener> {
+        MessageListener::bind(addr).await
+    }
+
+    async fn connect(&self, addr: &str) -> io::Result<MessageStream> {
+        MessageStream::connect(addr).await
+    }
+}
+
+#[async_trait]
+impl Listener for MessageListener {
+    async fn accept(&self) -> io::Result<(MessageStream, SocketAddr)> {
+        MessageListener::accept(self).await
+    }
+}
+
+/// Unix domain sockets, addressed by their path.
+#[cfg(unix)]
+pub struct Unix;
+
+#[cfg(unix)]
+pub struct UnixListener {
+    inner: tokio::net::UnixListener,
+    numbering: Numbering,
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl Transport for Unix {
+    type Listener = UnixListener;
+
+    async fn bind(&self, addr: &str) -> io::Result<UnixListener> {
+        Ok(UnixListener {
+            inner: tokio::net::UnixListener::bind(addr)?,
+            numbering: Numbering::default(),
+        })
+    }
+
+    async fn connect(&self, addr: &str) -> io::Result<MessageStream> {
+        let stream = tokio::net::UnixStream::connect(addr).await?;
+        Ok(MessageStream::over(stream, None))
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl Listener for UnixListener {
+    async fn accept(&self) -> io::Result<(MessageStream, SocketAddr)> {
+        let (stream, _) = self.inner.accept().await?;
+        Ok((MessageStream::over(stream, None), self.numbering.next()))
+    }
+}
+
+/// Bytes buffered in each direction of an in-memory connection.
+const MEMORY_BUFFER: usize = 64 * 1024;
+
+/// Connections within the process, addressed by any name. Clones share
+/// their names, so a Master and its clients in one test can find each other.
+#[derive(Clone, Default)]
+pub struct Memory {
+    listeners: Arc<std::sync::Mutex<HashMap<String, mpsc::Sender<DuplexStream>>>>,
+}
+
+pub struct MemoryListener {
+    incoming: Mutex<mpsc::Receiver<DuplexStream>>,
+    numbering: Numbering,
+}
+
+#[async_trait]
+impl Transport for Memory {
+    type Listener = MemoryListener;
+
+    async fn bind(&self, addr: &str) -> io::Result<MemoryListener> {
+        let mut listeners = self.listeners.lock().unwrap();
+        if listeners.get(addr).is_some_and(|tx| !tx.is_closed()) {
+            return Err(io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("'{}' is already bound", addr),
+            ));
+        }
+
+        let (tx, rx) = mpsc::channel(16);
+        listeners.insert(addr.to_string(), tx);
+        Ok(MemoryListener {
+            incoming: Mutex::new(rx),
+            numbering: Numbering::default(),
+        })
+    }
+
+    async fn connect(&self, addr: &str) -> io::Result<MessageStream> {
+        let refused = || {
+            io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("Nothing bound at '{}'", addr),
+            )
+        };
+        let tx = self
+            .listeners
+            .lock()
+            .unwrap()
+            .get(addr)
+            .cloned()
+            .ok_or_else(refused)?;
+
+        let (local, remote) = tokio::io::duplex(MEMORY_BUFFER);
+        tx.send(remote).await.map_err(|_| refused())?;
+        Ok(MessageStream::over(local, None))
+    }
+}
+
+#[async_trait]
+impl Listener for MemoryListener {
+    async fn accept(&self) -> io::Result<(MessageStream, SocketAddr)> {
+        let stream =
+            self.incoming.lock().await.recv().await.ok_or_else(|| {
+                io::Error::new(ErrorKind::NotConnected, "Memory transport dropped")
+            })?;
+        Ok((MessageStream::over(stream, None), self.numbering.next()))
+    }
+}
@@ -0,0 +1,139 @@
+//! WebSocket transport, for networks whose proxies only let HTTP through.
+//! Every frame travels as one binary WebSocket message.
+
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::tcp::MessageStream;
+use crate::transport::{Connection, Listener, Transport};
+
+/// Time given to a new connection to complete the WebSocket handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listens at and connects to `host:port`, using `ws://host:port/`.
+pub struct WebSocket;
+
+pub struct WebSocketListener {
+    incoming: Mutex<mpsc::Receiver<io::Result<(MessageStream, SocketAddr)>>>,
+    acceptor: JoinHandle<()>,
+}
+
+struct WebSocketConnection {
+    inner: WebSocketStream<TcpStream>,
+}
+
+#[async_trait]
+impl Transport for WebSocket {
+    type Listener = WebSocketListener;
+
+    async fn bind(&self, addr: &str) -> io::Result<WebSocketListener> {
+        let listener = TcpListener::bind(addr).await?;
+        let (tx, rx) = mpsc::channel(16);
+
+        Ok(WebSocketListener {
+            incoming: Mutex::new(rx),
+            acceptor: tokio::spawn(accept_all(listener, tx)),
+        })
+    }
+
+    async fn connect(&self, addr: &str) -> io::Result<MessageStream> {
+        let stream = TcpStream::connect(addr).await?;
+        let local_addr = stream.local_addr().ok();
+        let (inner, _) = tokio_tungstenite::client_async(format!("ws://{}/", addr), stream)
+            .await
+            .map_err(into_io)?;
+
+        Ok(MessageStream::over(
+            WebSocketConnection { inner },
+            local_addr,
+        ))
+    }
+}
+
+#[async_trait]
+impl Listener for WebSocketListener {
+    async fn accept(&self) -> io::Result<(MessageStream, SocketAddr)> {
+        self.incoming.lock().await.recv().await.unwrap_or_else(|| {
+            Err(io::Error::new(
+                ErrorKind::NotConnected,
+                "WebSocket listener stopped",
+            ))
+        })
+    }
+}
+
+impl Drop for WebSocketListener {
+    fn drop(&mut self) {
+        self.acceptor.abort();
+    }
+}
+
+/// Does the handshakes apart from `accept`, so a slow client holds up no other.
+async fn accept_all(
+    listener: TcpListener,
+    tx: mpsc::Sender<io::Result<(MessageStream, SocketAddr)>>,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+        };
+
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let local_addr = stream.local_addr().ok();
+            let handshake = tokio_tungstenite::accept_async(stream);
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok(inner)) => {
+                    let stream = MessageStream::over(WebSocketConnection { inner }, local_addr);
+                    let _ = tx.send(Ok((stream, addr))).await;
+                }
+                Ok(Err(e)) => log::debug!("WebSocket handshake with {} failed due to {}", addr, e),
+                Err(_) => log::debug!("WebSocket handshake with {} timed out", addr),
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl Connection for WebSocketConnection {
+    async fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner
+            .send(tungstenite::Message::binary(buf.to_vec()))
+            .await
+            .map_err(into_io)
+    }
+
+    async fn read_buf(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        loop {
+            match self.inner.next().await {
+                Some(Ok(tungstenite::Message::Binary(data))) if !data.is_empty() => {
+                    buf.extend_from_slice(&data);
+                    return Ok(data.len());
+                }
+                Some(Ok(tungstenite::Message::Close(_))) | None => return Ok(0),
+                // Pings are answered by tungstenite itself
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(into_io(e)),
+            }
+        }
+    }
+}
+
+fn into_io(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
@@ -0,0 +1,65 @@
+use cross_messages::quic::{Certificate, Quic};
+use cross_messages::{Header, Listener, Message, MessageKind, Tail, Transport, ID};
+use std::time::Duration;
+
+fn clipboard(body: String) -> Message {
+    Message {
+        header: Header {
+            kind: MessageKind::Clipboard,
+            target: ID::Master,
+            ttl_secs: None,
+        },
+        body,
+        tail: Tail {
+            from: ID::Unregistered,
+        },
+    }
+}
+
+#[tokio::test]
+async fn keeps_frames_in_order() {
+    let certificate = Certificate::from_seed(&[7; 32]).unwrap();
+    let fingerprint = certificate.fingerprint();
+    let listener = Quic::with_certificate(certificate)
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let addr = listener.local_addr().to_string();
+
+    let mut client = Quic::pinned(Some(fingerprint))
+        .connect(&addr)
+        .await
+        .unwrap();
+
+    let sent = vec![
+        clipboard("small".to_string()),
+        clipboard("large".repeat(64 * 1024)),
+        clipboard("small again".to_string()),
+    ];
+    for msg in &sent {
+        client.send(msg.clone()).await.unwrap();
+    }
+
+    // The stream is only announced to the Master along with the first frame
+    let (mut server, _) = listener.accept().await.unwrap();
+
+    for msg in &sent {
+        let received = tokio::time::timeout(Duration::from_secs(5), server.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&received, msg);
+    }
+}
+
+#[tokio::test]
+async fn refuses_other_certificates() {
+    let listener = Quic::with_certificate(Certificate::from_seed(&[7; 32]).unwrap())
+        .bind("127.0.0.1:0")
+        .await
+        .unwrap();
+    let addr = listener.local_addr().to_string();
+
+    let other = Certificate::from_seed(&[8; 32]).unwrap().fingerprint();
+    assert!(Quic::pinned(Some(other)).connect(&addr).await.is_err());
+}
@@ -0,0 +1,140 @@
+use config::{Config, Environment, File, FileFormat};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Prefix of env vars overriding config values, e.g. `CROSSLIVE_HOST_PORT`.
+/// Nested values are separated by `__`, e.g. `CROSSLIVE_RATE_LIMIT__BURST`.
+pub const ENV_PREFIX: &str = "CROSSLIVE";
+
+/// Env var with the path of the config file.
+pub const PATH_ENV: &str = "CROSSCONFIG";
+
+/// The sources of a config, in increasing precedence: the defaults of the
+/// config type, a TOML file, `CROSSLIVE_*` env vars and command line flags.
+#[derive(Clone, Debug, Default)]
+pub struct Layers {
+    file: Option<PathBuf>,
+    file_required: bool,
+    overrides: Vec<(String, String)>,
+}
+
+impl Layers {
+    /// Reads the file at `path`, else at `CROSSCONFIG`, else `file_name` in the
+    /// user config directory. Only a file that was asked for has to exist.
+    pub fn new(path: Option<String>, file_name: &str) -> Self {
+        let (file, file_required) = match path.or_else(|| std::env::var(PATH_ENV).ok()) {
+            Some(path) => (Some(PathBuf::from(path)), true),
+            None => (default_path(file_name), false),
+        };
+
+        if file.is_none() {
+            log::warn!("No config directory, using only env vars and flags");
+        }
+
+        Layers {
+            file,
+            file_required,
+            overrides: Vec::new(),
+        }
+    }
+
+    /// Overrides `key` with a value from the command line.
+    pub fn set(&mut self, key: &str, value: impl ToString) {
+        self.overrides.push((key.to_string(), value.to_string()));
+    }
+
+    pub fn set_all(&mut self, overrides: impl IntoIterator<Item = (String, String)>) {
+        self.overrides.extend(overrides);
+    }
+
+    /// The value of `key` given on the command line.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.overrides
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn file(&self) -> Option<&Path> {
+        self.file.as_deref()
+    }
+
+    pub fn load<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+        let mut builder = Config::builder();
+        if let Some(path) = &self.file {
+            log::info!("Loading config from {}", path.display());
+            builder = builder.add_source(
+                File::new(&path.to_string_lossy(), FileFormat::Toml).required(self.file_required),
+            );
+        }
+
+        builder = builder.add_source(
+            Environment::with_prefix(ENV_PREFIX)
+                .prefix_separator("_")
+                .separator("__"),
+        );
+
+        for (key, value) in &self.overrides {
+            builder = builder.set_override(key.as_str(), value.as_str())?;
+        }
+
+        let source = match &self.file {
+            Some(path) => format!("{} and {}_* env vars", path.display(), ENV_PREFIX),
+            None => format!("{}_* env vars", ENV_PREFIX),
+        };
+
+        builder
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .map_err(|e| anyhow::anyhow!("Invalid config from {}: {}", source, e))
+    }
+}
+
+/// `file_name` in the user config directory, if there is one.
+pub fn default_path(file_name: &str) -> Option<PathBuf> {
+    dirs::config_local_dir().map(|dir| dir.join(file_name))
+}
+
+/// Fails with every problem found in a loaded config, if any.
+pub fn validate(problems: Vec<String>) -> anyhow::Result<()> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    anyhow::bail!("Invalid config:\n  - {}", problems.join("\n  - "))
+}
+
+/// Parses the `key=value` of a `--set` flag.
+pub fn parse_override(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("Expected 'key=value', got '{}'", arg)),
+    }
+}
+
+/// The config as TOML for `--print-config`, with passwords and tokens hidden.
+pub fn to_redacted_toml<T: Serialize>(config: &T) -> anyhow::Result<String> {
+    let mut value = toml::Value::try_from(config)?;
+    redact(&mut value);
+    Ok(toml::to_string_pretty(&value)?)
+}
+
+fn redact(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                if value.is_str() && (key.contains("password") || key.contains("token")) {
+                    *value = toml::Value::String("<redacted>".to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        toml::Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
@@ -0,0 +1,5 @@
+pub mod layers;
+pub mod watch;
+
+pub use layers::*;
+pub use watch::ConfigWatcher;
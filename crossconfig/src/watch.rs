@@ -0,0 +1,81 @@
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Changes within this time are reported once, editors often write in steps.
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Notices changes to a config file, as well as SIGHUP on Unix.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    rx: mpsc::Receiver<()>,
+    _watcher: RecommendedWatcher,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let (tx, rx) = mpsc::channel(1);
+
+        // Editors replace files instead of writing them, so the directory is watched
+        let file_name = path.file_name().map(ToOwned::to_owned);
+        let file_tx = tx.clone();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+            let Ok(event) = res else {
+                return;
+            };
+
+            let relevant = (event.kind.is_create() || event.kind.is_modify())
+                && event
+                    .paths
+                    .iter()
+                    .any(|path| path.file_name() == file_name.as_deref());
+            if relevant {
+                let _ = file_tx.try_send(());
+            }
+        })?;
+
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hangup = signal(SignalKind::hangup())?;
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    log::info!("Received SIGHUP");
+                    if tx.send(()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        log::info!("Watching {} for changes", path.display());
+        Ok(ConfigWatcher {
+            path,
+            rx,
+            _watcher: watcher,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Waits for the next change, returns false once nothing can change anymore.
+    pub async fn changed(&mut self) -> bool {
+        if self.rx.recv().await.is_none() {
+            return false;
+        }
+
+        tokio::time::sleep(DEBOUNCE).await;
+        while self.rx.try_recv().is_ok() {}
+        true
+    }
+}
@@ -6,20 +6,12 @@ use std::thread;
 use std::time::{Duration, SystemTime};
 
 pub fn init_fern_logger() -> anyhow::Result<()> {
-    if let Ok(_) = std::env::var("NO_LOG") {
+    if std::env::var("NO_LOG").is_ok() {
         return Ok(());
     }
 
     let loglevel = match std::env::var("LOGLEVEL") {
-        Ok(v) => match v.as_str() {
-            "DEBUG" => log::LevelFilter::Debug,
-            "INFO" => log::LevelFilter::Info,
-            "OFF" => log::LevelFilter::Off,
-            "ERROR" => log::LevelFilter::Error,
-            "WARN" => log::LevelFilter::Warn,
-            "TRACE" => log::LevelFilter::Trace,
-            _ => panic!("Invalid LOGLEVEL input"),
-        },
+        Ok(v) => parse_level(&v).expect("Invalid LOGLEVEL input"),
         Err(_) => log::LevelFilter::Info,
     };
 
@@ -40,9 +32,10 @@ pub fn init_fern_logger() -> anyhow::Result<()> {
                 message,
             ))
         })
-        .level(loglevel);
+        // Filtered by the max level instead, so it can change at runtime
+        .level(log::LevelFilter::Trace);
 
-    if let Err(_) = std::env::var("NO_STDOUT") {
+    if std::env::var("NO_STDOUT").is_err() {
         fern_dis = fern_dis.chain(std::io::stdout());
     }
 
@@ -56,6 +49,47 @@ pub fn init_fern_logger() -> anyhow::Result<()> {
     }
 
     fern_dis.apply()?;
+    log::set_max_level(loglevel);
+    Ok(())
+}
+
+pub fn parse_level(level: &str) -> Option<log::LevelFilter> {
+    match level.to_uppercase().as_str() {
+        "DEBUG" => Some(log::LevelFilter::Debug),
+        "INFO" => Some(log::LevelFilter::Info),
+        "OFF" => Some(log::LevelFilter::Off),
+        "ERROR" => Some(log::LevelFilter::Error),
+        "WARN" => Some(log::LevelFilter::Warn),
+        "TRACE" => Some(log::LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+/// Applies a log level given on the command line, over `LOGLEVEL`.
+pub fn set_level(level: &str) -> anyhow::Result<()> {
+    let level = parse_level(level).ok_or_else(|| anyhow::anyhow!("Invalid log level '{}'", level))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Applies the log level of a config file, `LOGLEVEL` takes precedence.
+/// `None` restores the default level.
+pub fn set_config_level(level: Option<&str>) -> anyhow::Result<()> {
+    if std::env::var("LOGLEVEL").is_ok() {
+        return Ok(());
+    }
+
+    let level = match level {
+        Some(level) => {
+            parse_level(level).ok_or_else(|| anyhow::anyhow!("Invalid log level '{}'", level))?
+        }
+        None => log::LevelFilter::Info,
+    };
+
+    if log::max_level() != level {
+        log::set_max_level(level);
+        log::info!("Log level set to {}", level);
+    }
     Ok(())
 }
 
@@ -70,7 +104,6 @@ impl FileDateLogger {
         let file = OpenOptions::new()
             .create(true)
             .append(true)
-            .write(true)
             .open(path)?;
 
         let current_file = Arc::new(Mutex::new(file));
@@ -94,7 +127,6 @@ impl FileDateLogger {
                     let new_file = OpenOptions::new()
                         .create(true)
                         .append(true)
-                        .write(true)
                         .open(new_file_name)
                         .unwrap();
 
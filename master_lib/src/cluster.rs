@@ -0,0 +1,75 @@
+//! Runs several Masters as one, e.g. behind a load balancer.
+//!
+//! The nodes of a cluster are linked with each other by federation, sharing
+//! all groups, so a device can connect to any node and reach any other device.
+//!
+//! Only the register is shared. Accounts, pairing codes, policy and shared
+//! history stay on the node they were set up on, so a pairing code has to be
+//! redeemed on the node that issued it and accounts are refused by
+//! master_server in cluster mode.
+
+use crate::federation::{FederationConfig, PeerConfig, ALL_GROUPS};
+use serde::{Deserialize, Serialize};
+
+/// The same on every node, except for `name` and `listen`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ClusterConfig {
+    /// Name of this node, one of `nodes`.
+    pub name: String,
+    /// Address the other nodes connect to, as `host:port`.
+    pub listen: String,
+    /// Secret shared by all nodes.
+    pub token: String,
+    pub nodes: Vec<NodeConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NodeConfig {
+    pub name: String,
+    /// Where the node listens for the other nodes.
+    pub addr: String,
+}
+
+impl ClusterConfig {
+    /// Links this node with all other nodes. Of each pair of nodes, the one
+    /// with the lower name connects, so there is a single link between them.
+    pub fn federation(&self) -> FederationConfig {
+        let peers = self
+            .nodes
+            .iter()
+            .filter(|node| node.name != self.name)
+            .map(|node| PeerConfig {
+                name: node.name.clone(),
+                addr: (self.name < node.name).then(|| node.addr.clone()),
+                token: self.token.clone(),
+                groups: vec![ALL_GROUPS.to_string()],
+            })
+            .collect();
+
+        FederationConfig {
+            name: self.name.clone(),
+            listen: Some(self.listen.clone()),
+            peers,
+        }
+    }
+
+    /// Problems which would keep the node from joining the cluster.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !self.nodes.iter().any(|node| node.name == self.name) {
+            problems.push(format!(
+                "cluster.name '{}' is not one of the nodes",
+                self.name
+            ));
+        }
+        if self.token.is_empty() {
+            problems.push("cluster.token is empty".to_string());
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            if self.nodes[..i].iter().any(|other| other.name == node.name) {
+                problems.push(format!("cluster node '{}' is listed twice", node.name));
+            }
+        }
+        problems
+    }
+}
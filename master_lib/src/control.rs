@@ -0,0 +1,159 @@
+use crate::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+use tokio::sync::Notify;
+
+/// Live connections of the Master by their remote address.
+pub type Connections = Arc<RwLock<HashMap<SocketAddr, Connection>>>;
+
+pub struct Connection {
+    pub id: ID,
+    pub connected: SystemTime,
+    pub(crate) kick: Arc<Notify>,
+    pub(crate) direct: mpsc::Sender<Message>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConnectionInfo {
+    pub addr: SocketAddr,
+    pub id: String,
+    pub name: Option<String>,
+    pub group: Option<String>,
+    pub user: Option<String>,
+    pub connected_secs: u64,
+}
+
+/// Inspects and manages a running `MasterServer` from outside of it.
+#[derive(Clone)]
+pub struct MasterHandle {
+    pub(crate) register: Register,
+    pub(crate) connections: Connections,
+    pub(crate) policy: Arc<RwLock<Policy>>,
+    pub(crate) draining: Arc<AtomicBool>,
+}
+
+impl MasterHandle {
+    pub fn register(&self) -> &Register {
+        &self.register
+    }
+
+    /// All open connections, joined with the registered device if any.
+    pub async fn connections(&self) -> Vec<ConnectionInfo> {
+        let reg = self.register.read().await;
+        let mut list = self
+            .connections
+            .read()
+            .await
+            .iter()
+            .map(|(addr, conn)| {
+                let device = reg.iter().find(|device| device.id == conn.id);
+                ConnectionInfo {
+                    addr: *addr,
+                    id: conn.id.to_string(),
+                    name: device.map(|device| device.name.clone()),
+                    group: device.map(|device| device.scope.group.clone()),
+                    user: device.and_then(|device| device.scope.user.clone()),
+                    connected_secs: conn
+                        .connected
+                        .elapsed()
+                        .map(|elapsed| elapsed.as_secs())
+                        .unwrap_or_default(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        list.sort_by_key(|conn| conn.connected_secs);
+        list
+    }
+
+    /// Closes the connection of the device `id`.
+    pub async fn kick(&self, id: &ID) -> bool {
+        let connections = self.connections.read().await;
+        match connections.values().find(|conn| &conn.id == id) {
+            Some(conn) => {
+                log::info!("Kicking {}", id);
+                conn.kick.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn kick_addr(&self, addr: &SocketAddr) -> bool {
+        match self.connections.read().await.get(addr) {
+            Some(conn) => {
+                log::info!("Kicking connection {}", addr);
+                conn.kick.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn rename(&self, id: &ID, name: &str) -> bool {
+        let mut reg = self.register.write().await;
+        match reg.iter_mut().find(|device| &device.id == id) {
+            Some(device) => {
+                device.name = name.to_string();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sends a `Notice` to every local device, returning how many got it.
+    pub async fn notice(&self, notice: &str) -> usize {
+        self.send_local(MessageKind::Notice, notice).await
+    }
+
+    /// Applies to every message relayed from now on. Direct connections
+    /// between devices are closed, they would skip the new policy.
+    pub async fn set_policy(&self, policy: Policy) {
+        *self.policy.write().await = policy;
+        self.send_local(MessageKind::Rendezvous, "").await;
+    }
+
+    /// Queues a message from the Master on the connection of every local
+    /// device, returning on how many it was queued.
+    async fn send_local(&self, kind: MessageKind, body: &str) -> usize {
+        let reg = self.register.read().await;
+        let connections = self.connections.read().await;
+        let mut sent = 0;
+        for conn in connections.values() {
+            let local = reg
+                .iter()
+                .any(|device| device.id == conn.id && device.origin.is_none());
+            if !local {
+                continue;
+            }
+
+            let msg = Message {
+                header: Header {
+                    kind,
+                    target: conn.id.clone(),
+                    ttl_secs: None,
+                },
+                body: body.to_string(),
+                tail: Tail { from: ID::Master },
+            };
+            match conn.direct.try_send(msg) {
+                Ok(()) => sent += 1,
+                Err(e) => log::warn!("Failed to send {:?} to {:?} due to {}", kind, conn.id, e),
+            }
+        }
+
+        sent
+    }
+
+    /// While draining, new connections are refused and open ones are kept.
+    pub fn set_draining(&self, draining: bool) {
+        log::info!("Draining set to {}", draining);
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,516 @@
+//! Links Masters as peers, so devices connected to either can reach each other.
+//!
+//! Each Master advertises its local devices of the groups allowed on a link,
+//! and relays messages for devices advertised by the peer across the link.
+//! Devices learned from a peer are never advertised again and messages from
+//! a link are only delivered to local devices, so messages can't loop.
+
+use crate::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+
+/// Shares every group when listed in the groups of a peer.
+pub const ALL_GROUPS: &str = "*";
+
+/// Time given to a peer to complete the handshake.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FederationConfig {
+    /// Name of this Master, as configured at its peers.
+    pub name: String,
+    /// Address peers connect to, as `host:port`.
+    pub listen: Option<String>,
+    #[serde(default)]
+    pub peers: Vec<PeerConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PeerConfig {
+    pub name: String,
+    /// Connects to the peer if set, else waits for the peer to connect.
+    pub addr: Option<String>,
+    /// Secret both Masters have configured for the link.
+    pub token: String,
+    /// Groups whose devices are shared over the link, `*` for all of them.
+    pub groups: Vec<String>,
+}
+
+pub struct Federation {
+    config: FederationConfig,
+    register: Register,
+    broadcast: broadcast::Sender<Message>,
+    policy: Arc<RwLock<Policy>>,
+    /// Names of the peers with an open link.
+    linked: Arc<std::sync::Mutex<HashSet<String>>>,
+}
+
+impl Federation {
+    pub(crate) fn new(
+        config: FederationConfig,
+        register: Register,
+        broadcast: broadcast::Sender<Message>,
+        policy: Arc<RwLock<Policy>>,
+    ) -> Self {
+        Federation {
+            config,
+            register,
+            broadcast,
+            policy,
+            linked: Arc::default(),
+        }
+    }
+
+    /// Connects to the peers with an address and accepts the others.
+    pub async fn run(self) -> anyhow::Result<()> {
+        let federation = Arc::new(self);
+
+        for peer in &federation.config.peers {
+            if let Some(addr) = &peer.addr {
+                tokio::spawn(federation.clone().dial(peer.clone(), addr.clone()));
+            }
+        }
+
+        let Some(listen) = &federation.config.listen else {
+            return Ok(());
+        };
+
+        let listener = MessageListener::bind(listen).await?;
+        log::info!("Accepting federation links on {}", listen);
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let federation = federation.clone();
+            tokio::spawn(async move {
+                if let Err(e) = federation.accept(stream).await {
+                    log::warn!("Federation link from {} failed due to {}", addr, e);
+                }
+            });
+        }
+    }
+
+    /// Keeps a link to `peer` open, reconnecting with a growing delay.
+    async fn dial(self: Arc<Self>, peer: PeerConfig, addr: String) {
+        let mut delay = Duration::from_secs(1);
+        loop {
+            match self.connect(&peer, &addr).await {
+                Ok(stream) => {
+                    delay = Duration::from_secs(1);
+                    if let Err(e) = self.link(stream, &peer).await {
+                        log::warn!("Federation link to '{}' failed due to {}", peer.name, e);
+                    }
+                }
+                Err(e) => log::warn!("Failed to link with '{}' due to {}", peer.name, e),
+            }
+
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(Duration::from_secs(60));
+        }
+    }
+
+    async fn connect(&self, peer: &PeerConfig, addr: &str) -> anyhow::Result<MessageStream> {
+        let mut stream = MessageStream::connect(addr).await?;
+        self.send_hello(&mut stream, peer).await?;
+        let theirs = self.recv_hello(&mut stream).await?;
+        anyhow::ensure!(
+            theirs.name == peer.name,
+            "Expected Master '{}' at {}, found '{}'",
+            peer.name,
+            addr,
+            theirs.name
+        );
+        Ok(stream)
+    }
+
+    async fn accept(&self, mut stream: MessageStream) -> anyhow::Result<()> {
+        let peer = self.recv_hello(&mut stream).await?;
+        self.send_hello(&mut stream, peer).await?;
+        self.link(stream, peer).await
+    }
+
+    async fn send_hello(
+        &self,
+        stream: &mut MessageStream,
+        peer: &PeerConfig,
+    ) -> anyhow::Result<()> {
+        let hello = FederationHello {
+            name: self.config.name.clone(),
+            token: peer.token.clone(),
+        };
+        stream
+            .send(master_message(MessageKind::Federate, &hello)?)
+            .await?;
+        Ok(())
+    }
+
+    /// Waits for the hello of a configured peer with a matching token.
+    async fn recv_hello(&self, stream: &mut MessageStream) -> anyhow::Result<&PeerConfig> {
+        let msg = tokio::time::timeout(HELLO_TIMEOUT, stream.recv()).await??;
+        if msg.header.kind == MessageKind::Error {
+            anyhow::bail!("Peer refused: {}", ErrorReply::from_message(&msg)?);
+        }
+        anyhow::ensure!(
+            msg.header.kind == MessageKind::Federate,
+            "Expected Federate, got {:?}",
+            msg.header.kind
+        );
+
+        let hello: FederationHello = serde_json::from_str(&msg.body)?;
+        let peer = self
+            .config
+            .peers
+            .iter()
+            .find(|peer| peer.name == hello.name && hello.name != self.config.name);
+
+        match peer {
+            Some(peer) if bool::from(peer.token.as_bytes().ct_eq(hello.token.as_bytes())) => {
+                Ok(peer)
+            }
+            _ => {
+                let e = ErrorReply::Unauthorized(format!(
+                    "Unknown peer '{}' or invalid token",
+                    hello.name
+                ));
+                let _ = stream.send(e.to_message(ID::Master)?).await;
+                anyhow::bail!("Refused peer '{}', unknown or invalid token", hello.name)
+            }
+        }
+    }
+
+    /// Runs an established link until either side closes it.
+    async fn link(&self, stream: MessageStream, peer: &PeerConfig) -> anyhow::Result<()> {
+        if !self.linked.lock().unwrap().insert(peer.name.clone()) {
+            anyhow::bail!("Already linked with '{}'", peer.name);
+        }
+
+        log::info!(
+            "Linked with Master '{}' for groups {:?}",
+            peer.name,
+            peer.groups
+        );
+        let mut link = Link {
+            federation: self,
+            peer,
+            stream,
+            advertised: HashMap::new(),
+        };
+        let res = link.run().await;
+
+        self.linked.lock().unwrap().remove(&peer.name);
+        self.forget(&peer.name).await;
+        log::info!("Link with Master '{}' closed", peer.name);
+        res
+    }
+
+    /// Removes all devices of `peer` from the register.
+    async fn forget(&self, peer: &str) {
+        let mut reg = self.register.write().await;
+        let (gone, kept) = reg
+            .drain(..)
+            .partition(|device| device.origin.as_deref() == Some(peer));
+        *reg = kept;
+        drop(reg);
+
+        for device in gone {
+            self.inform_local(&device, MessageKind::ClosedRegDevice)
+                .await;
+        }
+    }
+
+    /// Tells local devices in the scope of `device` that it came or went.
+    async fn inform_local(&self, device: &Device, kind: MessageKind) {
+        let Ok(body) = serde_json::to_string(&device.id) else {
+            return;
+        };
+
+        let reg = self.register.read().await;
+        for local in reg
+            .iter()
+            .filter(|local| local.origin.is_none() && local.scope == device.scope)
+        {
+            let _ = self.broadcast.send(Message {
+                header: Header {
+                    kind,
+                    target: local.id.clone(),
+                    ttl_secs: None,
+                },
+                body: body.clone(),
+                tail: Tail { from: ID::Master },
+            });
+        }
+    }
+}
+
+struct Link<'a> {
+    federation: &'a Federation,
+    peer: &'a PeerConfig,
+    stream: MessageStream,
+    /// Local devices the peer knows about.
+    advertised: HashMap<ID, Scope>,
+}
+
+impl Link<'_> {
+    async fn run(&mut self) -> anyhow::Result<()> {
+        let mut broad_recv = self.federation.broadcast.subscribe();
+        self.sync().await?;
+
+        loop {
+            tokio::select! {
+                res = self.stream.recv() => {
+                    let msg = match res {
+                        Ok(msg) => msg,
+                        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                        Err(e) => return Err(e.into()),
+                    };
+                    self.receive(msg).await?;
+                }
+
+                res = broad_recv.recv() => {
+                    match res {
+                        Ok(msg) => self.relay(msg).await?,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            log::warn!("Link to '{}' skipped {} messages", self.peer.name, skipped);
+                            self.sync().await?;
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+        }
+    }
+
+    fn allows(&self, scope: &Scope) -> bool {
+        self.peer
+            .groups
+            .iter()
+            .any(|group| group == ALL_GROUPS || group == &scope.group)
+    }
+
+    /// Brings the devices the peer knows about up to date with the register.
+    async fn sync(&mut self) -> anyhow::Result<()> {
+        let current = self
+            .federation
+            .register
+            .read()
+            .await
+            .iter()
+            .filter(|device| device.origin.is_none() && self.allows(&device.scope))
+            .cloned()
+            .collect::<Vec<Device>>();
+
+        let gone = self
+            .advertised
+            .keys()
+            .filter(|id| !current.iter().any(|device| &device.id == *id))
+            .cloned()
+            .collect::<Vec<ID>>();
+        for id in gone {
+            self.advertised.remove(&id);
+            let msg = master_message(MessageKind::ClosedRegDevice, &id)?;
+            self.stream.send(msg).await?;
+        }
+
+        for device in current {
+            if self.advertised.get(&device.id) == Some(&device.scope) {
+                continue;
+            }
+
+            let announce = FederatedDevice {
+                id: device.id.clone(),
+                name: device.name.clone(),
+                group: device.scope.group.clone(),
+                user: device.scope.user.clone(),
+            };
+            self.stream
+                .send(master_message(MessageKind::NewRegDevice, &announce)?)
+                .await?;
+            self.advertised.insert(device.id, device.scope);
+        }
+
+        Ok(())
+    }
+
+    /// Sends a locally relayed message across, if it is for a device of the peer.
+    async fn relay(&mut self, msg: Message) -> anyhow::Result<()> {
+        if msg.header.target == ID::Master {
+            if matches!(
+                msg.header.kind,
+                MessageKind::NewRegDevice | MessageKind::ClosedRegDevice
+            ) {
+                self.sync().await?;
+            }
+            return Ok(());
+        }
+
+        // Only messages of local devices, the peer can't answer anything else
+        if !self.advertised.contains_key(&msg.tail.from) {
+            return Ok(());
+        }
+
+        let for_peer = self.federation.register.read().await.iter().any(|device| {
+            device.id == msg.header.target && device.origin.as_ref() == Some(&self.peer.name)
+        });
+        if for_peer {
+            self.stream.send(msg).await?;
+        }
+        Ok(())
+    }
+
+    async fn receive(&mut self, msg: Message) -> anyhow::Result<()> {
+        if msg.header.target == ID::Master {
+            return self.update(msg).await;
+        }
+
+        let from = self
+            .federation
+            .register
+            .read()
+            .await
+            .iter()
+            .find(|device| {
+                device.id == msg.tail.from && device.origin.as_ref() == Some(&self.peer.name)
+            })
+            .cloned();
+        let Some(from) = from else {
+            log::warn!(
+                "Dropped message from '{}' for unknown sender {:?}",
+                self.peer.name,
+                msg.tail.from
+            );
+            return Ok(());
+        };
+
+        let to_local = self
+            .federation
+            .register
+            .read()
+            .await
+            .iter()
+            .any(|device| device.id == msg.header.target && device.origin.is_none());
+        if !to_local || !self.allows(&from.scope) {
+            log::warn!(
+                "Dropped message from '{}' to {:?}, not reachable over the link",
+                self.peer.name,
+                msg.header.target
+            );
+            return Ok(());
+        }
+
+        let res = check_relay(
+            &self.federation.register,
+            &self.federation.policy,
+            &from.id,
+            &from.scope,
+            &msg,
+        )
+        .await;
+        if let Err(e) = res {
+            log::warn!(
+                "Refused message from {:?} over '{}' due to {}",
+                from.id,
+                self.peer.name,
+                e
+            );
+            return Ok(());
+        }
+
+        let _ = self.federation.broadcast.send(msg);
+        Ok(())
+    }
+
+    /// Applies a change to the devices of the peer.
+    async fn update(&mut self, msg: Message) -> anyhow::Result<()> {
+        match msg.header.kind {
+            MessageKind::NewRegDevice => {
+                let announce: FederatedDevice = serde_json::from_str(&msg.body)?;
+                let device = Device {
+                    id: announce.id,
+                    name: announce.name,
+                    scope: Scope {
+                        group: announce.group,
+                        user: announce.user,
+                    },
+                    origin: Some(self.peer.name.clone()),
+                };
+                if !self.allows(&device.scope) {
+                    log::warn!(
+                        "Ignored device of '{}' in group '{}', not allowed on the link",
+                        self.peer.name,
+                        device.scope.group
+                    );
+                    return Ok(());
+                }
+
+                let mut reg = self.federation.register.write().await;
+                if let Some(known) = reg.iter().find(|known| known.id == device.id) {
+                    if known.origin != device.origin {
+                        log::warn!(
+                            "Ignored device {:?} of '{}', it is already known",
+                            device.id,
+                            self.peer.name
+                        );
+                        return Ok(());
+                    }
+                }
+                let old = reg
+                    .iter()
+                    .position(|known| known.id == device.id)
+                    .map(|i| reg.remove(i));
+                reg.push(device.clone());
+                drop(reg);
+
+                if let Some(old) = old {
+                    self.federation
+                        .inform_local(&old, MessageKind::ClosedRegDevice)
+                        .await;
+                }
+                log::info!("Device {:?} of '{}' joined", device.id, self.peer.name);
+                self.federation
+                    .inform_local(&device, MessageKind::NewRegDevice)
+                    .await;
+            }
+
+            MessageKind::ClosedRegDevice => {
+                let id: ID = serde_json::from_str(&msg.body)?;
+                let mut reg = self.federation.register.write().await;
+                let gone = reg
+                    .iter()
+                    .position(|device| {
+                        device.id == id && device.origin.as_ref() == Some(&self.peer.name)
+                    })
+                    .map(|i| reg.remove(i));
+                drop(reg);
+
+                if let Some(gone) = gone {
+                    log::info!("Device {:?} of '{}' left", id, self.peer.name);
+                    self.federation
+                        .inform_local(&gone, MessageKind::ClosedRegDevice)
+                        .await;
+                }
+            }
+
+            MessageKind::Error => log::warn!(
+                "Peer '{}' refused: {}",
+                self.peer.name,
+                ErrorReply::from_message(&msg)?
+            ),
+
+            kind => log::debug!("Ignored {:?} from '{}'", kind, self.peer.name),
+        }
+
+        Ok(())
+    }
+}
+
+fn master_message(kind: MessageKind, body: &impl Serialize) -> serde_json::Result<Message> {
+    Ok(Message {
+        header: Header {
+            kind,
+            target: ID::Master,
+            ttl_secs: None,
+        },
+        body: serde_json::to_string(body)?,
+        tail: Tail { from: ID::Master },
+    })
+}
@@ -1,3 +1,6 @@
+use crate::history::SharedHistory;
+use crate::pairing::Pairing;
+use crate::users::{DeviceAccess, UserStore};
 use crate::*;
 use cross_messages::*;
 
@@ -10,7 +13,13 @@ pub struct Context<'a> {
     pub message: Message,
     pub register: &'a Register,
     pub broadcast: &'a mut broadcast::Sender<Message>,
+    pub stream: &'a mut MessageStream,
+    pub accounts: Option<&'a UserStore>,
+    pub pairing: &'a Pairing,
+    pub history: Option<&'a SharedHistory>,
+    pub fingerprint: Option<&'a str>,
     pub id_ref: &'a mut ID,
+    pub scope_ref: &'a mut Scope,
 }
 
 #[derive(Clone)]
@@ -29,6 +38,31 @@ impl MessageHandler for DefaultMessageHandler {
                 default_get_reg_devices(&mut ctx).await?;
             }
 
+            MessageKind::GetPeers => {
+                default_get_peers(&mut ctx).await?;
+            }
+
+            MessageKind::Admin => {
+                default_admin(&mut ctx).await?;
+            }
+
+            MessageKind::PairingCode => {
+                default_pairing_code(&mut ctx).await?;
+            }
+
+            MessageKind::Pair => {
+                log::info!("Pairing new Device");
+                default_pair(&mut ctx).await?;
+            }
+
+            MessageKind::JoinGroup => {
+                default_join_group(&mut ctx).await?;
+            }
+
+            MessageKind::HistoryPush | MessageKind::HistoryList | MessageKind::HistoryGet => {
+                default_history(&mut ctx).await?;
+            }
+
             MessageKind::Close => {
                 log::info!("Closing Connection to {:#?}", ctx.id_ref);
                 default_close(&mut ctx).await?;
@@ -43,19 +77,45 @@ impl MessageHandler for DefaultMessageHandler {
 }
 
 pub async fn default_register(ctx: &mut Context<'_>) -> anyhow::Result<()> {
+    let request = RegisterRequest::from_message(&ctx.message)?;
+
+    let user = match authenticate(ctx, &request).await {
+        Ok(user) => user,
+        Err(e) => {
+            log::warn!("Refused registration due to {}", e);
+            ctx.stream.send(e.to_message(ID::Unregistered)?).await?;
+            return Err(e.into());
+        }
+    };
+
     let new_id = ID::new_slave();
     *ctx.id_ref = new_id.clone();
+    *ctx.scope_ref = Scope {
+        group: request.group,
+        user,
+    };
+
+    let name = request.device.unwrap_or_else(|| new_id.to_string());
 
     let mut write_reg = ctx.register.write().await;
-    log::info!("Writing new ID into Register");
-    write_reg.push(new_id.clone());
+    log::info!("Writing new ID into Register ({:?})", ctx.scope_ref);
+    write_reg.push(Device {
+        id: new_id.clone(),
+        name,
+        scope: ctx.scope_ref.clone(),
+        origin: None,
+    });
     drop(write_reg);
 
-    let new_id_str = serde_json::to_string(&new_id)?;
+    let new_id_str = serde_json::to_string(&RegisterReply {
+        id: new_id.clone(),
+        fingerprint: ctx.fingerprint.map(str::to_string),
+    })?;
 
     let header = Header {
         kind: MessageKind::Reply,
         target: new_id,
+        ttl_secs: None,
     };
     let tail = Tail { from: ID::Master };
     let msg = Message {
@@ -69,13 +129,170 @@ pub async fn default_register(ctx: &mut Context<'_>) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Checks the credentials of `request` against the accounts of the Master.
+/// Returns the authenticated user, or `None` if no accounts are configured.
+async fn authenticate(
+    ctx: &Context<'_>,
+    request: &RegisterRequest,
+) -> Result<Option<String>, ErrorReply> {
+    let Some(accounts) = ctx.accounts else {
+        return Ok(None);
+    };
+
+    // Revocations are per device, so logins without one are refused
+    let (Some(user), Some(device)) = (request.user.clone(), request.device.clone()) else {
+        return Err(ErrorReply::Unauthorized("Missing credentials".to_string()));
+    };
+    let (password, token) = (request.password.clone(), request.token.clone());
+
+    let checked = accounts
+        .blocking(move |accounts| {
+            check_credentials(
+                accounts,
+                &user,
+                &device,
+                password.as_deref(),
+                token.as_deref(),
+            )
+        })
+        .await;
+
+    match checked {
+        Ok(res) => res.map(Some),
+        Err(e) => {
+            log::error!("Failed to check credentials due to {}", e);
+            Err(ErrorReply::Unauthorized(
+                "Failed to check credentials".to_string(),
+            ))
+        }
+    }
+}
+
+/// The outer error is a failure of the store, the inner one a refused login.
+fn check_credentials(
+    accounts: &UserStore,
+    user: &str,
+    device: &str,
+    password: Option<&str>,
+    token: Option<&str>,
+) -> anyhow::Result<Result<String, ErrorReply>> {
+    let invalid = || ErrorReply::Unauthorized("Invalid credentials".to_string());
+
+    match (password, token) {
+        (Some(password), _) => {
+            let Some(user) = accounts.authenticate(user, password)? else {
+                return Ok(Err(invalid()));
+            };
+
+            Ok(match accounts.login_device(&user.name, device)? {
+                DeviceAccess::Allowed => Ok(user.name),
+                DeviceAccess::Unknown => Err(ErrorReply::Forbidden(format!(
+                    "Unknown device '{}', pair it from another of your devices",
+                    device
+                ))),
+                DeviceAccess::Revoked => Err(ErrorReply::Forbidden(format!(
+                    "Device '{}' was revoked",
+                    device
+                ))),
+            })
+        }
+        (None, Some(token)) => Ok(accounts
+            .authenticate_device(user, device, token)?
+            .map(|user| user.name)
+            .ok_or_else(invalid)),
+        (None, None) => Ok(Err(ErrorReply::Unauthorized(
+            "Missing credentials".to_string(),
+        ))),
+    }
+}
+
+pub async fn default_pairing_code(ctx: &mut Context<'_>) -> anyhow::Result<()> {
+    if *ctx.id_ref == ID::Unregistered {
+        let e = ErrorReply::Unauthorized("Register before requesting a pairing code".to_string());
+        ctx.stream.send(e.to_message(ID::Unregistered)?).await?;
+        return Ok(());
+    }
+
+    let code = ctx.pairing.issue(ctx.scope_ref.clone());
+    log::info!("Issued pairing code for {:?}", ctx.scope_ref);
+
+    let msg = Message {
+        header: Header {
+            kind: MessageKind::Reply,
+            target: ctx.id_ref.clone(),
+            ttl_secs: None,
+        },
+        body: serde_json::to_string(&code)?,
+        tail: Tail { from: ID::Master },
+    };
+
+    ctx.broadcast.send(msg)?;
+    Ok(())
+}
+
+/// Redeems a pairing code for an unregistered device.
+/// The reply is sent directly, as the device has no ID to be reached by.
+pub async fn default_pair(ctx: &mut Context<'_>) -> anyhow::Result<()> {
+    let reply = match pairing_grant(ctx).await {
+        Ok(grant) => Message {
+            header: Header {
+                kind: MessageKind::Reply,
+                target: ID::Unregistered,
+                ttl_secs: None,
+            },
+            body: serde_json::to_string(&grant)?,
+            tail: Tail { from: ID::Master },
+        },
+        Err(e) => {
+            log::warn!("Refused pairing due to {}", e);
+            e.to_message(ID::Unregistered)?
+        }
+    };
+
+    ctx.stream.send(reply).await?;
+    Ok(())
+}
+
+async fn pairing_grant(ctx: &Context<'_>) -> Result<PairingGrant, ErrorReply> {
+    let redeem: PairRedeem = serde_json::from_str(&ctx.message.body).map_err(bad_request)?;
+    let scope = ctx
+        .pairing
+        .redeem(&redeem.code)
+        .ok_or_else(|| ErrorReply::Unauthorized("Invalid or expired pairing code".to_string()))?;
+
+    let token = match (&scope.user, ctx.accounts) {
+        (Some(user), Some(accounts)) => {
+            let (user, device) = (user.clone(), redeem.device.clone());
+            let issued = accounts
+                .blocking(move |accounts| accounts.issue_device_token(&user, &device))
+                .await
+                .map_err(|e| {
+                    log::error!("Failed to issue a device token due to {}", e);
+                    ErrorReply::Forbidden("Failed to issue a device token".to_string())
+                })?;
+            Some(issued.ok_or_else(|| {
+                ErrorReply::Forbidden(format!("Device '{}' was revoked", redeem.device))
+            })?)
+        }
+        _ => None,
+    };
+
+    Ok(PairingGrant {
+        group: scope.group,
+        user: scope.user,
+        device: redeem.device,
+        token,
+        fingerprint: ctx.fingerprint.map(str::to_string),
+    })
+}
+
 pub async fn default_get_reg_devices(ctx: &mut Context<'_>) -> anyhow::Result<()> {
     let read_reg = ctx.register.read().await;
     let list = serde_json::to_string(
-        &*read_reg
+        &read_reg
             .iter()
-            .filter(|item| item != &ctx.id_ref)
-            .map(|id| id.clone())
+            .filter(|device| &device.id != ctx.id_ref && &device.scope == ctx.scope_ref)
+            .map(|device| device.id.clone())
             .collect::<Vec<ID>>(),
     )?;
     drop(read_reg);
@@ -83,6 +300,7 @@ pub async fn default_get_reg_devices(ctx: &mut Context<'_>) -> anyhow::Result<()
     let header = Header {
         kind: MessageKind::Reply,
         target: ctx.message.tail.from.clone(),
+        ttl_secs: None,
     };
 
     let tail = Tail { from: ID::Master };
@@ -98,11 +316,179 @@ pub async fn default_get_reg_devices(ctx: &mut Context<'_>) -> anyhow::Result<()
     Ok(())
 }
 
+/// Replies with the names of the other devices of the group, which
+/// `GetRegDevices` only lists by ID.
+pub async fn default_get_peers(ctx: &mut Context<'_>) -> anyhow::Result<()> {
+    if *ctx.id_ref == ID::Unregistered {
+        let e = ErrorReply::Unauthorized("Register before listing peers".to_string());
+        ctx.stream.send(e.to_message(ID::Unregistered)?).await?;
+        return Ok(());
+    }
+
+    let peers = ctx
+        .register
+        .read()
+        .await
+        .iter()
+        .filter(|device| &device.id != ctx.id_ref && &device.scope == ctx.scope_ref)
+        .map(|device| Peer {
+            id: device.id.clone(),
+            name: device.name.clone(),
+        })
+        .collect::<Vec<_>>();
+
+    ctx.broadcast.send(Message {
+        header: Header {
+            kind: MessageKind::Reply,
+            target: ctx.id_ref.clone(),
+            ttl_secs: None,
+        },
+        body: serde_json::to_string(&peers)?,
+        tail: Tail { from: ID::Master },
+    })?;
+    Ok(())
+}
+
+/// Moves the sender into the group named by the body, without registering again.
+/// Replies with the devices of the new group, like `GetRegDevices`.
+pub async fn default_join_group(ctx: &mut Context<'_>) -> anyhow::Result<()> {
+    let group = ctx.message.body.trim().to_string();
+    let refused = if *ctx.id_ref == ID::Unregistered {
+        Some(ErrorReply::Unauthorized(
+            "Register before joining a group".to_string(),
+        ))
+    } else if group.is_empty() {
+        Some(ErrorReply::BadRequest("Missing group".to_string()))
+    } else {
+        None
+    };
+
+    if let Some(e) = refused {
+        ctx.stream.send(e.to_message(ctx.id_ref.clone())?).await?;
+        return Ok(());
+    }
+
+    if group != ctx.scope_ref.group {
+        log::info!(
+            "Moving {:?} from group '{}' to '{}'",
+            ctx.id_ref,
+            ctx.scope_ref.group,
+            group
+        );
+        inform_update_reg(ctx, MessageKind::ClosedRegDevice).await?;
+
+        ctx.scope_ref.group = group;
+        for device in ctx.register.write().await.iter_mut() {
+            if &device.id == ctx.id_ref {
+                device.scope = ctx.scope_ref.clone();
+            }
+        }
+
+        inform_update_reg(ctx, MessageKind::NewRegDevice).await?;
+    }
+
+    default_get_reg_devices(ctx).await
+}
+
+/// Stores `HistoryPush`, which only gets a reply on errors, and answers
+/// `HistoryList` and `HistoryGet`.
+pub async fn default_history(ctx: &mut Context<'_>) -> anyhow::Result<()> {
+    match history_reply(ctx).await {
+        Ok(None) => {}
+        Ok(Some(body)) => {
+            let msg = Message {
+                header: Header {
+                    kind: MessageKind::Reply,
+                    target: ctx.id_ref.clone(),
+                    ttl_secs: None,
+                },
+                body,
+                tail: Tail { from: ID::Master },
+            };
+            ctx.broadcast.send(msg)?;
+        }
+        Err(e) => {
+            ctx.stream.send(e.to_message(ctx.id_ref.clone())?).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn history_reply(ctx: &Context<'_>) -> Result<Option<String>, ErrorReply> {
+    let history = shared_history(ctx)?;
+    let body = &ctx.message.body;
+
+    let reply = match ctx.message.header.kind {
+        MessageKind::HistoryPush => {
+            let entry: HistoryPush = serde_json::from_str(body).map_err(bad_request)?;
+            if entry.content.len() > history.max_entry_bytes() {
+                return Err(ErrorReply::TooLarge(format!(
+                    "History entry of {} bytes exceeds limit of {}",
+                    entry.content.len(),
+                    history.max_entry_bytes()
+                )));
+            }
+
+            let device = device_name(ctx).await;
+            let scope = ctx.scope_ref.clone();
+            let id = history
+                .blocking({
+                    let device = device.clone();
+                    move |history| history.push(&scope, &device, &entry)
+                })
+                .await
+                .map_err(internal)?;
+            log::debug!("Added history entry {} of '{}'", id, device);
+            return Ok(None);
+        }
+        MessageKind::HistoryGet => {
+            let id: i64 = body.trim().parse().map_err(bad_request)?;
+            let scope = ctx.scope_ref.clone();
+            let entry = history
+                .blocking(move |history| history.get(&scope, id))
+                .await
+                .map_err(internal)?
+                .ok_or_else(|| ErrorReply::NotFound(format!("No history entry {}", id)))?;
+            serde_json::to_string(&entry)
+        }
+        _ => {
+            let query: HistoryQuery = serde_json::from_str(body).map_err(bad_request)?;
+            let scope = ctx.scope_ref.clone();
+            let entries = history
+                .blocking(move |history| history.list(&scope, query.device.as_deref(), query.limit))
+                .await
+                .map_err(internal)?;
+            serde_json::to_string(&entries)
+        }
+    };
+    reply.map(Some).map_err(bad_request)
+}
+
+fn shared_history<'a>(ctx: &Context<'a>) -> Result<&'a SharedHistory, ErrorReply> {
+    if *ctx.id_ref == ID::Unregistered {
+        return Err(ErrorReply::Unauthorized(
+            "Register before using the history".to_string(),
+        ));
+    }
+    ctx.history
+        .ok_or_else(|| ErrorReply::BadRequest("This Master keeps no shared history".to_string()))
+}
+
+async fn device_name(ctx: &Context<'_>) -> String {
+    ctx.register
+        .read()
+        .await
+        .iter()
+        .find(|device| &device.id == ctx.id_ref)
+        .map(|device| device.name.clone())
+        .unwrap_or_else(|| ctx.id_ref.to_string())
+}
+
 pub async fn default_close(ctx: &mut Context<'_>) -> anyhow::Result<()> {
     let mut write_reg = ctx.register.write().await;
     let mut i = None;
-    for (index, id) in write_reg.iter().enumerate() {
-        if id == ctx.id_ref {
+    for (index, device) in write_reg.iter().enumerate() {
+        if &device.id == ctx.id_ref {
             i = Some(index);
             break;
         }
@@ -126,10 +512,14 @@ pub async fn default_close(ctx: &mut Context<'_>) -> anyhow::Result<()> {
 pub async fn inform_update_reg(ctx: &mut Context<'_>, kind: MessageKind) -> anyhow::Result<()> {
     log::info!("Updating other registered Devices");
     let reg = ctx.register.read().await;
-    for id in reg.iter().filter(|i| i != &ctx.id_ref) {
+    for device in reg
+        .iter()
+        .filter(|device| &device.id != ctx.id_ref && &device.scope == ctx.scope_ref)
+    {
         let header = Header {
-            target: id.clone(),
+            target: device.id.clone(),
             kind,
+            ttl_secs: None,
         };
         let tail = Tail { from: ID::Master };
         let body = serde_json::to_string(&ctx.id_ref)?;
@@ -137,6 +527,194 @@ pub async fn inform_update_reg(ctx: &mut Context<'_>, kind: MessageKind) -> anyh
 
         ctx.broadcast.send(msg)?;
     }
+    drop(reg);
+
+    // Lets federation links know the register changed
+    let _ = ctx.broadcast.send(Message {
+        header: Header {
+            target: ID::Master,
+            kind,
+            ttl_secs: None,
+        },
+        body: serde_json::to_string(&ctx.id_ref)?,
+        tail: Tail { from: ID::Master },
+    });
 
     Ok(())
 }
+
+pub async fn default_admin(ctx: &mut Context<'_>) -> anyhow::Result<()> {
+    let reply = match admin_reply(ctx).await {
+        Ok(body) => Message {
+            header: Header {
+                kind: MessageKind::Reply,
+                target: ctx.id_ref.clone(),
+                ttl_secs: None,
+            },
+            body,
+            tail: Tail { from: ID::Master },
+        },
+        Err(e) => {
+            log::warn!("Refused admin request from {:?} due to {}", ctx.id_ref, e);
+            e.to_message(ctx.id_ref.clone())?
+        }
+    };
+
+    ctx.broadcast.send(reply)?;
+    Ok(())
+}
+
+async fn admin_reply(ctx: &mut Context<'_>) -> Result<String, ErrorReply> {
+    let accounts = ctx
+        .accounts
+        .ok_or_else(|| ErrorReply::BadRequest("No accounts configured".to_string()))?
+        .clone();
+
+    let is_admin = match ctx.scope_ref.user.clone() {
+        Some(user) => accounts
+            .blocking(move |accounts| accounts.user(&user))
+            .await
+            .map_err(bad_request)?
+            .map(|user| user.admin)
+            .unwrap_or(false),
+        None => false,
+    };
+
+    if !is_admin {
+        return Err(ErrorReply::Forbidden("Admin rights required".to_string()));
+    }
+
+    let request: AdminRequest = serde_json::from_str(&ctx.message.body).map_err(bad_request)?;
+    log::info!("Handling {:?}", request);
+
+    let body = match request {
+        AdminRequest::ListUsers => serde_json::to_string(
+            &accounts
+                .blocking(|accounts| accounts.users())
+                .await
+                .map_err(bad_request)?,
+        ),
+        AdminRequest::AddUser {
+            name,
+            password,
+            admin,
+        } => {
+            let added = name.clone();
+            accounts
+                .blocking(move |accounts| accounts.add_user(&added, &password, admin))
+                .await
+                .map_err(bad_request)?;
+            serde_json::to_string(&name)
+        }
+        AdminRequest::RemoveUser { name } => {
+            kick_user_devices(ctx, &name, None).await;
+            serde_json::to_string(
+                &accounts
+                    .blocking(move |accounts| accounts.remove_user(&name))
+                    .await
+                    .map_err(bad_request)?,
+            )
+        }
+        AdminRequest::ListDevices { user } => serde_json::to_string(
+            &accounts
+                .blocking(move |accounts| accounts.devices(&user))
+                .await
+                .map_err(bad_request)?,
+        ),
+        AdminRequest::RevokeDevice { user, device } => {
+            let (revoked_user, revoked_device) = (user.clone(), device.clone());
+            let revoked = accounts
+                .blocking(move |accounts| accounts.revoke_device(&revoked_user, &revoked_device))
+                .await
+                .map_err(bad_request)?;
+            kick_user_devices(ctx, &user, Some(&device)).await;
+            serde_json::to_string(&revoked)
+        }
+        AdminRequest::RenameDevice {
+            user,
+            device,
+            new_name,
+        } => {
+            let (renamed_user, renamed_device, renamed_to) =
+                (user.clone(), device.clone(), new_name.clone());
+            let renamed = accounts
+                .blocking(move |accounts| {
+                    accounts.rename_device(&renamed_user, &renamed_device, &renamed_to)
+                })
+                .await
+                .map_err(bad_request)?;
+
+            for registered in ctx.register.write().await.iter_mut() {
+                if registered.scope.user.as_ref() == Some(&user) && registered.name == device {
+                    registered.name = new_name.clone();
+                }
+            }
+
+            serde_json::to_string(&renamed)
+        }
+    };
+
+    body.map_err(bad_request)
+}
+
+/// Closes the connections of `user`, optionally only those of `device`.
+async fn kick_user_devices(ctx: &mut Context<'_>, user: &str, device: Option<&str>) {
+    let targets = ctx
+        .register
+        .read()
+        .await
+        .iter()
+        .filter(|registered| registered.scope.user.as_deref() == Some(user))
+        .filter(|registered| device.is_none_or(|device| registered.name == device))
+        .map(|registered| registered.id.clone())
+        .collect::<Vec<ID>>();
+
+    for id in targets {
+        log::info!("Kicking {:?} of user '{}'", id, user);
+        let _ = ctx.broadcast.send(kick_message(id));
+    }
+}
+
+/// Adds the address the sender of a `Rendezvous` is seen at to its candidates,
+/// which is the one to reach it by from outside of its network.
+pub fn add_observed_candidate(mut msg: Message, addr: SocketAddr) -> Message {
+    let Ok(mut offer) = serde_json::from_str::<RendezvousOffer>(&msg.body) else {
+        return msg;
+    };
+
+    // Transports without addresses number connections as the unspecified address
+    if addr.ip().is_unspecified() {
+        return msg;
+    }
+
+    let observed = SocketAddr::new(addr.ip(), offer.port);
+    if !offer.candidates.contains(&observed) {
+        offer.candidates.push(observed);
+    }
+    if let Ok(body) = serde_json::to_string(&offer) {
+        msg.body = body;
+    }
+    msg
+}
+
+/// A `Close` from the Master ends the connection of `target`.
+pub fn kick_message(target: ID) -> Message {
+    Message {
+        header: Header {
+            kind: MessageKind::Close,
+            target,
+            ttl_secs: None,
+        },
+        body: String::new(),
+        tail: Tail { from: ID::Master },
+    }
+}
+
+fn bad_request(e: impl std::fmt::Display) -> ErrorReply {
+    ErrorReply::BadRequest(e.to_string())
+}
+
+fn internal(e: anyhow::Error) -> ErrorReply {
+    log::error!("History failed due to {}", e);
+    ErrorReply::Internal("History is unavailable".to_string())
+}
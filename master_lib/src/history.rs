@@ -0,0 +1,175 @@
+use crate::Scope;
+use cross_messages::{HistoryEntry, HistoryPush};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Retention of the shared history, which is kept per group.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct HistoryConfig {
+    /// Sqlite database, the history is lost on restart if not set.
+    pub database: Option<String>,
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+    pub max_age_secs: Option<u64>,
+    /// Larger entries are refused.
+    #[serde(default = "default_max_entry_bytes")]
+    pub max_entry_bytes: usize,
+}
+
+fn default_max_entries() -> usize {
+    100
+}
+
+fn default_max_entry_bytes() -> usize {
+    1024 * 1024
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            database: None,
+            max_entries: default_max_entries(),
+            max_age_secs: None,
+            max_entry_bytes: default_max_entry_bytes(),
+        }
+    }
+}
+
+/// Clipboards pushed by devices, readable by every device of the same scope.
+/// Clones share the same connection.
+#[derive(Clone)]
+pub struct SharedHistory {
+    conn: Arc<Mutex<Connection>>,
+    config: Arc<HistoryConfig>,
+}
+
+impl SharedHistory {
+    pub fn open(config: HistoryConfig) -> anyhow::Result<Self> {
+        let conn = match &config.database {
+            Some(path) => Connection::open(path)?,
+            None => Connection::open_in_memory()?,
+        };
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                grp TEXT NOT NULL,
+                user TEXT,
+                created INTEGER NOT NULL,
+                device TEXT NOT NULL,
+                content_type TEXT NOT NULL,
+                content TEXT NOT NULL,
+                encrypted INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS history_scope ON history (grp, user, id);",
+        )?;
+
+        Ok(SharedHistory {
+            conn: Arc::new(Mutex::new(conn)),
+            config: Arc::new(config),
+        })
+    }
+
+    /// Runs `f` on the blocking thread pool, as sqlite would otherwise stall
+    /// the async executor.
+    pub async fn blocking<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&SharedHistory) -> anyhow::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let history = self.clone();
+        tokio::task::spawn_blocking(move || f(&history)).await?
+    }
+
+    pub fn max_entry_bytes(&self) -> usize {
+        self.config.max_entry_bytes
+    }
+
+    /// Adds an entry and drops those of the scope past the retention.
+    pub fn push(&self, scope: &Scope, device: &str, entry: &HistoryPush) -> anyhow::Result<i64> {
+        let now = unix_now();
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO history (grp, user, created, device, content_type, content, encrypted)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                scope.group,
+                scope.user,
+                now,
+                device,
+                entry.content_type,
+                entry.content,
+                entry.encrypted
+            ],
+        )?;
+        let id = conn.last_insert_rowid();
+
+        if let Some(max_age) = self.config.max_age_secs {
+            conn.execute(
+                "DELETE FROM history WHERE grp = ?1 AND user IS ?2 AND created < ?3",
+                params![scope.group, scope.user, now - max_age as i64],
+            )?;
+        }
+        conn.execute(
+            "DELETE FROM history WHERE grp = ?1 AND user IS ?2 AND id NOT IN
+                (SELECT id FROM history WHERE grp = ?1 AND user IS ?2 ORDER BY id DESC LIMIT ?3)",
+            params![scope.group, scope.user, self.config.max_entries as i64],
+        )?;
+        Ok(id)
+    }
+
+    /// The latest entries of the scope, newest first.
+    pub fn list(
+        &self,
+        scope: &Scope,
+        device: Option<&str>,
+        limit: usize,
+    ) -> anyhow::Result<Vec<HistoryEntry>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT id, created, device, content_type, content, encrypted FROM history
+             WHERE grp = ?1 AND user IS ?2 AND (?3 IS NULL OR device = ?3)
+             ORDER BY id DESC LIMIT ?4",
+        )?;
+        let entries = stmt
+            .query_map(
+                params![scope.group, scope.user, device, limit as i64],
+                entry_from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    pub fn get(&self, scope: &Scope, id: i64) -> anyhow::Result<Option<HistoryEntry>> {
+        Ok(self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT id, created, device, content_type, content, encrypted FROM history
+                 WHERE grp = ?1 AND user IS ?2 AND id = ?3",
+                params![scope.group, scope.user, id],
+                entry_from_row,
+            )
+            .optional()?)
+    }
+}
+
+fn entry_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<HistoryEntry> {
+    Ok(HistoryEntry {
+        id: row.get(0)?,
+        created: row.get(1)?,
+        device: row.get(2)?,
+        content_type: row.get(3)?,
+        content: row.get(4)?,
+        encrypted: row.get(5)?,
+    })
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
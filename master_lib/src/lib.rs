@@ -1,24 +1,130 @@
+pub mod cluster;
+pub mod control;
+pub mod federation;
 pub mod handler;
+pub mod history;
+pub mod metrics;
+pub mod middleware;
+pub mod pairing;
+pub mod policy;
+pub mod ratelimit;
+pub mod shutdown;
+pub mod users;
 
+use crate::control::{Connection, Connections, MasterHandle};
+use crate::federation::{Federation, FederationConfig};
 use crate::handler::*;
+use crate::history::SharedHistory;
+use crate::metrics::Metrics;
+use crate::middleware::{ConnInfo, Flow, Layer, Stack};
+use crate::pairing::Pairing;
+use crate::policy::Policy;
+use crate::shutdown::{InFlight, Phase, ShutdownHandle, ShutdownOptions};
+use crate::users::UserStore;
 use cross_messages::*;
 
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 pub use tokio;
 pub(crate) use tokio::{
     net::ToSocketAddrs,
-    sync::{broadcast, RwLock},
+    sync::{broadcast, mpsc, watch, RwLock},
 };
 
-pub type Register = Arc<RwLock<Vec<ID>>>;
+pub type Register = Arc<RwLock<Vec<Device>>>;
 
-pub struct MasterServer<T>
+/// Messages from the Master queued per connection, see `MasterHandle::notice`.
+const DIRECT_CAPACITY: usize = 16;
+
+/// Relayed messages kept for slow connections. A registration alone sends
+/// one message per device, so this has to fit a burst for large groups.
+const BROADCAST_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Device {
+    pub id: ID,
+    pub name: String,
+    pub scope: Scope,
+    /// Name of the federated Master the device is connected to, `None` if local.
+    pub origin: Option<String>,
+}
+
+/// Devices only see and reach devices with an equal scope.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Scope {
+    pub group: String,
+    pub user: Option<String>,
+}
+
+impl Default for Scope {
+    fn default() -> Self {
+        Scope {
+            group: DEFAULT_GROUP.to_string(),
+            user: None,
+        }
+    }
+}
+
+pub async fn is_registered(register: &Register, target: &ID) -> bool {
+    register
+        .read()
+        .await
+        .iter()
+        .any(|device| &device.id == target)
+}
+
+/// Checks that `msg` may be relayed from the device `id` to its target.
+async fn check_relay(
+    register: &Register,
+    policy: &RwLock<Policy>,
+    id: &ID,
+    scope: &Scope,
+    msg: &Message,
+) -> Result<(), ErrorReply> {
+    let reg = register.read().await;
+    let from = reg.iter().find(|device| &device.id == id);
+    let to = reg
+        .iter()
+        .find(|device| device.id == msg.header.target && &device.scope == scope);
+
+    let (Some(from), Some(to)) = (from, to) else {
+        return Err(ErrorReply::Forbidden(format!(
+            "{:?} is not reachable",
+            msg.header.target
+        )));
+    };
+
+    match policy.read().await.check(msg.header.kind, from, to) {
+        policy::Action::Allow => Ok(()),
+        policy::Action::Deny => Err(ErrorReply::Forbidden(format!(
+            "{:?} to '{}' denied by policy",
+            msg.header.kind, to.name
+        ))),
+    }
+}
+
+pub struct MasterServer<T, L = MessageListener>
 where
     T: MessageHandler,
+    L: Listener,
 {
-    listener: MessageListener,
+    listener: L,
     register: Register,
     sender: broadcast::Sender<Message>,
+    accounts: Option<Arc<UserStore>>,
+    policy: Arc<RwLock<Policy>>,
+    pairing: Arc<Pairing>,
+    history: Option<Arc<SharedHistory>>,
+    fingerprint: Option<Arc<str>>,
+    layers: Stack,
+    metrics: Arc<Metrics>,
+    connections: Connections,
+    draining: Arc<AtomicBool>,
+    shutdown: Arc<watch::Sender<Option<ShutdownOptions>>>,
+    phase: watch::Sender<Phase>,
+    in_flight: InFlight,
     handler: T,
 }
 
@@ -27,36 +133,201 @@ where
     T: MessageHandler + 'static,
 {
     pub async fn new(addr: impl ToSocketAddrs, handler: T) -> std::io::Result<Self> {
-        Ok(MasterServer {
-            listener: MessageListener::bind(addr).await?,
+        Ok(MasterServer::with_listener(
+            MessageListener::bind(addr).await?,
+            handler,
+        ))
+    }
+}
+
+impl<T, L> MasterServer<T, L>
+where
+    T: MessageHandler + 'static,
+    L: Listener,
+{
+    /// Serves devices connecting through `transport` at `addr`, e.g. `Unix`
+    /// or `Memory`, which can't be picked as a `TransportKind`.
+    pub async fn bind<X>(transport: &X, addr: &str, handler: T) -> std::io::Result<Self>
+    where
+        X: Transport<Listener = L>,
+    {
+        Ok(MasterServer::with_listener(
+            transport.bind(addr).await?,
+            handler,
+        ))
+    }
+
+    /// Serves devices connecting through any transport, bound with `Transport::bind`.
+    pub fn with_listener(listener: L, handler: T) -> Self {
+        MasterServer {
+            listener,
             register: Register::default(),
-            sender: broadcast::channel(12).0,
+            sender: broadcast::channel(BROADCAST_CAPACITY).0,
+            accounts: None,
+            policy: Arc::default(),
+            pairing: Arc::default(),
+            history: None,
+            fingerprint: None,
+            layers: Stack::default(),
+            metrics: Arc::default(),
+            connections: Connections::default(),
+            draining: Arc::default(),
+            shutdown: Arc::new(watch::channel(None).0),
+            phase: watch::channel(Phase::Running).0,
+            in_flight: InFlight::default(),
             handler,
-        })
+        }
     }
 
-    pub fn set_listener(&mut self, listener: MessageListener) {
+    pub fn set_listener(&mut self, listener: L) {
         self.listener = listener;
     }
 
+    /// Requires devices to authenticate as a user of `accounts` on register.
+    pub fn set_accounts(&mut self, accounts: Arc<UserStore>) {
+        self.accounts = Some(accounts);
+    }
+
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.policy = Arc::new(RwLock::new(policy));
+    }
+
+    pub fn set_pairing(&mut self, pairing: Pairing) {
+        self.pairing = Arc::new(pairing);
+    }
+
+    /// Keeps a history per group, which devices push to and read from.
+    pub fn set_history(&mut self, history: Arc<SharedHistory>) {
+        self.history = Some(history);
+    }
+
+    /// Identifies this Master to devices, which may pin it.
+    pub fn set_fingerprint(&mut self, fingerprint: &str) {
+        self.fingerprint = Some(fingerprint.into());
+    }
+
+    /// Adds a middleware layer, which wraps the handler and message relaying.
+    pub fn add_layer(&mut self, layer: impl Layer + 'static) {
+        self.layers.push(layer);
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn handle(&self) -> MasterHandle {
+        MasterHandle {
+            register: self.register.clone(),
+            connections: self.connections.clone(),
+            policy: self.policy.clone(),
+            draining: self.draining.clone(),
+        }
+    }
+
+    /// Links this Master with the peers of `config`, run it next to `run`.
+    pub fn federation(&self, config: FederationConfig) -> Federation {
+        Federation::new(
+            config,
+            self.register.clone(),
+            self.sender.clone(),
+            self.policy.clone(),
+        )
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            request: self.shutdown.clone(),
+        }
+    }
+
+    /// Accepts connections until a shutdown is requested through a `ShutdownHandle`.
     pub async fn run(&mut self) -> anyhow::Result<()> {
+        let mut shutdown = self.shutdown.subscribe();
         loop {
-            let (msg_stream, addr) = self.listener.accept().await?;
+            let (msg_stream, addr) = tokio::select! {
+                res = self.listener.accept() => res?,
+                _ = shutdown.wait_for(Option::is_some) => break,
+            };
+
+            if self.draining.load(Ordering::Relaxed) {
+                log::info!("Refused Connection {:?} while draining", addr);
+                continue;
+            }
 
             log::info!("New Connection: {:?}", addr);
 
+            let kick = Arc::new(tokio::sync::Notify::new());
+            let (direct_sender, direct) = mpsc::channel(DIRECT_CAPACITY);
+            self.connections.write().await.insert(
+                addr,
+                Connection {
+                    id: ID::Unregistered,
+                    connected: SystemTime::now(),
+                    kick: kick.clone(),
+                    direct: direct_sender,
+                },
+            );
+
             let cloned_reg = self.register.clone();
             let cloned_board = self.sender.clone();
             let stream_handler = StreamHandler {
                 stream: msg_stream,
                 register: cloned_reg,
                 broadcast: cloned_board,
+                accounts: self.accounts.clone(),
+                policy: self.policy.clone(),
+                pairing: self.pairing.clone(),
+                history: self.history.clone(),
+                fingerprint: self.fingerprint.clone(),
+                layers: self.layers.clone(),
+                metrics: self.metrics.clone(),
+                connections: self.connections.clone(),
+                kick,
+                direct,
+                phase: self.phase.subscribe(),
+                in_flight: self.in_flight.clone(),
                 handler: self.handler.clone(),
+                addr,
                 id: ID::Unregistered,
+                scope: Scope::default(),
             };
 
             tokio::spawn(async move { stream_handler.handle().await });
         }
+
+        let options = shutdown.borrow().clone().unwrap_or_default();
+        self.shut_down(options).await;
+        Ok(())
+    }
+
+    /// Stops reading from devices, lets in-flight relays finish and then
+    /// closes every connection with a `Shutdown` message.
+    async fn shut_down(&mut self, options: ShutdownOptions) {
+        let deadline = tokio::time::Instant::now() + options.deadline;
+        let poll = std::time::Duration::from_millis(10);
+        log::info!(
+            "Shutting down {} connections, waiting up to {:?}",
+            self.connections.read().await.len(),
+            options.deadline
+        );
+
+        self.phase.send_replace(Phase::Stopping);
+        while !self.in_flight.is_empty() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(poll).await;
+        }
+
+        self.phase.send_replace(Phase::Closing(options.notice));
+        while !self.connections.read().await.is_empty() && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(poll).await;
+        }
+
+        let open = self.connections.read().await.len();
+        if open > 0 {
+            log::warn!(
+                "{} connections still open after the shutdown deadline",
+                open
+            );
+        }
     }
 }
 
@@ -67,8 +338,23 @@ where
     stream: MessageStream,
     register: Register,
     broadcast: broadcast::Sender<Message>,
+    accounts: Option<Arc<UserStore>>,
+    policy: Arc<RwLock<Policy>>,
+    pairing: Arc<Pairing>,
+    history: Option<Arc<SharedHistory>>,
+    fingerprint: Option<Arc<str>>,
+    layers: Stack,
+    metrics: Arc<Metrics>,
+    connections: Connections,
+    kick: Arc<tokio::sync::Notify>,
+    /// Messages from the Master to this device only, see `MasterHandle`.
+    direct: mpsc::Receiver<Message>,
+    phase: watch::Receiver<Phase>,
+    in_flight: InFlight,
     handler: T,
+    addr: SocketAddr,
     id: ID,
+    scope: Scope,
 }
 
 impl<T> StreamHandler<T>
@@ -76,14 +362,129 @@ where
     T: MessageHandler,
 {
     pub async fn handle(mut self) -> anyhow::Result<()> {
+        let connected = Instant::now();
+        self.metrics.connection_opened();
+
+        let res = self.handle_loop().await;
+
+        let conn = ConnInfo {
+            addr: self.addr,
+            id: &self.id,
+            scope: &self.scope,
+            register: &self.register,
+            broadcast: &self.broadcast,
+        };
+        self.layers.on_disconnect(&conn).await;
+
+        // Devices that disconnect without `Close` or got kicked are still registered
+        if is_registered(&self.register, &self.id).await {
+            let mut ctx = Context {
+                message: Message {
+                    header: Header {
+                        kind: MessageKind::Close,
+                        target: ID::Master,
+                        ttl_secs: None,
+                    },
+                    body: String::new(),
+                    tail: Tail {
+                        from: self.id.clone(),
+                    },
+                },
+                register: &self.register,
+                broadcast: &mut self.broadcast,
+                stream: &mut self.stream,
+                accounts: self.accounts.as_deref(),
+                pairing: &self.pairing,
+                history: self.history.as_deref(),
+                fingerprint: self.fingerprint.as_deref(),
+                id_ref: &mut self.id,
+                scope_ref: &mut self.scope,
+            };
+
+            default_close(&mut ctx).await?;
+        }
+
+        self.connections.write().await.remove(&self.addr);
+        self.metrics
+            .set_registered_devices(self.register.read().await.len());
+        self.metrics.connection_closed(connected.elapsed());
+        res
+    }
+
+    async fn handle_loop(&mut self) -> anyhow::Result<()> {
         let mut broad_recv = self.broadcast.subscribe();
+        let mut stopping = false;
+        let mut phase_open = true;
         loop {
+            // Follows reloads of the layers, e.g. a changed body quota
+            self.stream
+                .set_max_frame_len(self.layers.max_frame_len().unwrap_or(DEFAULT_MAX_FRAME_LEN));
+
             tokio::select! {
-                res = self.stream.recv() => {
-                    let msg = res?;
+                res = self.stream.recv(), if !stopping => {
+                    let msg = match res {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            let Some(frame) = FrameTooLarge::from_io(&e) else {
+                                return Err(e.into());
+                            };
+                            log::warn!("Skipped message from {:?} due to {}", self.id, frame);
+
+                            let conn = ConnInfo {
+                                addr: self.addr,
+                                id: &self.id,
+                                scope: &self.scope,
+                                register: &self.register,
+                                broadcast: &self.broadcast,
+                            };
+
+                            match self.layers.on_oversized(&conn, frame).await {
+                                Flow::Reject(e) => {
+                                    self.stream.send(e.to_message(self.id.clone())?).await?;
+                                }
+                                Flow::Disconnect => {
+                                    log::info!("Layer closed Connection to {:?}", self.id);
+                                    return Ok(());
+                                }
+                                _ => {}
+                            }
+                            continue;
+                        }
+                    };
+                    let _in_flight = self.in_flight.start();
+                    self.metrics
+                        .received(msg.header.kind, self.stream.last_frame_len() as u64);
 
                     log::info!("New {:#?}", msg);
 
+                    if let Err(e) = policy::check_origin(&msg, &self.id) {
+                        log::warn!("Refused message due to {}", e);
+                        self.stream.send(e.to_message(self.id.clone())?).await?;
+                        continue;
+                    }
+
+                    let conn = ConnInfo {
+                        addr: self.addr,
+                        id: &self.id,
+                        scope: &self.scope,
+                        register: &self.register,
+                        broadcast: &self.broadcast,
+                    };
+
+                    let msg = match self.layers.on_message(&conn, msg).await {
+                        Flow::Continue(msg) => msg,
+                        Flow::Reject(e) => {
+                            log::warn!("Layer refused message due to {}", e);
+                            self.stream.send(e.to_message(self.id.clone())?).await?;
+                            continue;
+                        }
+                        Flow::Stop => continue,
+                        Flow::Disconnect => {
+                            log::info!("Layer closed Connection to {:?}", self.id);
+                            return Ok(());
+                        }
+                    };
+
                     match msg.header.target {
                         ID::Master => {
                             log::info!("Creating new context");
@@ -91,31 +492,166 @@ where
                                 message: msg,
                                 register: &self.register,
                                 broadcast: &mut self.broadcast,
+                                stream: &mut self.stream,
+                                accounts: self.accounts.as_deref(),
+                                pairing: &self.pairing,
+                                history: self.history.as_deref(),
+                                fingerprint: self.fingerprint.as_deref(),
                                 id_ref: &mut self.id,
+                                scope_ref: &mut self.scope,
                             };
 
-                            self.handler.handle(ctx).await?;
+                            let kind = ctx.message.header.kind;
+                            let res = self.handler.handle(ctx).await;
+                            self.metrics
+                                .set_registered_devices(self.register.read().await.len());
+
+                            if let Some(conn) = self.connections.write().await.get_mut(&self.addr) {
+                                conn.id = self.id.clone();
+                            }
+
+                            if let Err(e) = res {
+                                if kind != MessageKind::Close {
+                                    self.metrics.handler_error();
+                                }
+                                return Err(e);
+                            }
                         }
 
                         ID::Unregistered => continue,
 
                         _ => {
+                            let res = check_relay(
+                                &self.register,
+                                &self.policy,
+                                &self.id,
+                                &self.scope,
+                                &msg,
+                            )
+                            .await;
+
+                            if let Err(e) = res {
+                                log::warn!(
+                                    "Refused message from {:?} to {:?} due to {}",
+                                    self.id,
+                                    msg.header.target,
+                                    e
+                                );
+                                self.stream.send(e.to_message(self.id.clone())?).await?;
+                                continue;
+                            }
+
+                            let msg = match msg.header.kind {
+                                MessageKind::Rendezvous => add_observed_candidate(msg, self.addr),
+                                _ => msg,
+                            };
+
+                            self.metrics.relayed(msg.header.kind);
                             let _ = self.broadcast.send(msg)?;
                         }
                     }
                 }
 
-                res = broad_recv.recv() => {
-                    let msg = res?;
+                _ = self.kick.notified() => {
+                    log::info!("Closed Connection to {:?} by Master", self.id);
+                    let _ = self.stream.send(kick_message(self.id.clone())).await;
+                    return Ok(());
+                }
 
-                    if msg.header.target != self.id {
-                        continue
+                res = self.phase.changed(), if phase_open => {
+                    if res.is_err() {
+                        phase_open = false;
+                        continue;
                     }
 
-                    log::info!("Bounced {:#?} to {:#?}", msg, self.id);
-                    self.stream.send(msg).await?;
+                    let phase = self.phase.borrow_and_update().clone();
+                    match phase {
+                        Phase::Running => {}
+                        Phase::Stopping => stopping = true,
+                        Phase::Closing(notice) => {
+                            loop {
+                                let msg = match broad_recv.try_recv() {
+                                    Ok(msg) => msg,
+                                    Err(broadcast::error::TryRecvError::Lagged(skipped)) => {
+                                        self.metrics.broadcast_lag(skipped);
+                                        continue;
+                                    }
+                                    Err(_) => break,
+                                };
+
+                                if self.forward(msg).await? {
+                                    return Ok(());
+                                }
+                            }
+
+                            log::info!("Closed Connection to {:?} for shutdown", self.id);
+                            let _ = self.stream.send(notice.to_message(self.id.clone())?).await;
+                            return Ok(());
+                        }
+                    }
                 }
+
+                Some(msg) = self.direct.recv() => {
+                    if self.forward(msg).await? {
+                        return Ok(());
+                    }
+                }
+
+                res = broad_recv.recv() => {
+                    let msg = match res {
+                        Ok(msg) => msg,
+                        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                            log::warn!("{:?} lagged behind, skipped {} messages", self.id, skipped);
+                            self.metrics.broadcast_lag(skipped);
+                            continue;
+                        }
+                        Err(e) => return Err(e.into()),
+                    };
+
+                    if self.forward(msg).await? {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends a relayed message to the device if it is the target.
+    /// Returns true if the connection has to be closed afterwards.
+    async fn forward(&mut self, msg: Message) -> anyhow::Result<bool> {
+        if msg.header.target != self.id {
+            return Ok(false);
+        }
+
+        let conn = ConnInfo {
+            addr: self.addr,
+            id: &self.id,
+            scope: &self.scope,
+            register: &self.register,
+            broadcast: &self.broadcast,
+        };
+
+        let msg = match self.layers.on_outgoing(&conn, msg).await {
+            Flow::Continue(msg) => msg,
+            Flow::Reject(e) => {
+                log::warn!("Layer withheld message due to {}", e);
+                return Ok(false);
             }
+            Flow::Stop => return Ok(false),
+            Flow::Disconnect => {
+                log::info!("Layer closed Connection to {:?}", self.id);
+                return Ok(true);
+            }
+        };
+
+        log::info!("Bounced {:#?} to {:#?}", msg, self.id);
+        let kicked = msg.header.kind == MessageKind::Close && msg.tail.from == ID::Master;
+        let sent = self.stream.send(msg).await?;
+        self.metrics.sent(sent as u64);
+
+        if kicked {
+            log::info!("Closed Connection to {:?} by Master", self.id);
         }
+        Ok(kicked)
     }
 }
@@ -0,0 +1,189 @@
+use cross_messages::MessageKind;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds in seconds of the connection duration histogram.
+const DURATION_BUCKETS: [f64; 9] = [
+    1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0, 21600.0, 86400.0, 604800.0,
+];
+
+/// Counters of a running Master, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    connections: AtomicI64,
+    registered_devices: AtomicI64,
+    received: Mutex<BTreeMap<String, u64>>,
+    relayed: Mutex<BTreeMap<String, u64>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    handler_errors: AtomicU64,
+    broadcast_lags: AtomicU64,
+    lagged_messages: AtomicU64,
+    durations: Mutex<Histogram>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Metrics {
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self, duration: Duration) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+
+        let secs = duration.as_secs_f64();
+        let mut histogram = self.durations.lock().unwrap();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += secs;
+    }
+
+    pub fn set_registered_devices(&self, count: usize) {
+        self.registered_devices
+            .store(count as i64, Ordering::Relaxed);
+    }
+
+    pub fn received(&self, kind: MessageKind, bytes: u64) {
+        *self
+            .received
+            .lock()
+            .unwrap()
+            .entry(kind_label(kind))
+            .or_default() += 1;
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn relayed(&self, kind: MessageKind) {
+        *self
+            .relayed
+            .lock()
+            .unwrap()
+            .entry(kind_label(kind))
+            .or_default() += 1;
+    }
+
+    pub fn sent(&self, bytes: u64) {
+        self.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn handler_error(&self) {
+        self.handler_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn broadcast_lag(&self, skipped: u64) {
+        self.broadcast_lags.fetch_add(1, Ordering::Relaxed);
+        self.lagged_messages.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        gauge(
+            &mut out,
+            "crosslive_connections",
+            "Open connections",
+            self.connections.load(Ordering::Relaxed),
+        );
+        gauge(
+            &mut out,
+            "crosslive_registered_devices",
+            "Registered devices",
+            self.registered_devices.load(Ordering::Relaxed),
+        );
+        counter_by_kind(
+            &mut out,
+            "crosslive_messages_received_total",
+            "Messages received from devices",
+            &self.received.lock().unwrap(),
+        );
+        counter_by_kind(
+            &mut out,
+            "crosslive_messages_relayed_total",
+            "Messages relayed between devices",
+            &self.relayed.lock().unwrap(),
+        );
+        counter(
+            &mut out,
+            "crosslive_bytes_in_total",
+            "Bytes received from devices",
+            self.bytes_in.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "crosslive_bytes_out_total",
+            "Bytes sent to devices",
+            self.bytes_out.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "crosslive_handler_errors_total",
+            "Errors returned by the message handler",
+            self.handler_errors.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "crosslive_broadcast_lag_total",
+            "Times a connection fell behind the broadcast channel",
+            self.broadcast_lags.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "crosslive_broadcast_lagged_messages_total",
+            "Messages skipped by connections that fell behind",
+            self.lagged_messages.load(Ordering::Relaxed),
+        );
+
+        let histogram = self.durations.lock().unwrap();
+        let name = "crosslive_connection_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Duration of closed connections", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (count, bound) in histogram.buckets.iter().zip(DURATION_BUCKETS) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, histogram.count);
+        let _ = writeln!(out, "{}_sum {}", name, histogram.sum);
+        let _ = writeln!(out, "{}_count {}", name, histogram.count);
+
+        out
+    }
+}
+
+fn kind_label(kind: MessageKind) -> String {
+    match kind {
+        MessageKind::Custom(n) => format!("Custom{}", n),
+        kind => format!("{:?}", kind),
+    }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: i64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn counter_by_kind(out: &mut String, name: &str, help: &str, values: &BTreeMap<String, u64>) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (kind, value) in values {
+        let _ = writeln!(out, "{}{{kind=\"{}\"}} {}", name, kind, value);
+    }
+}
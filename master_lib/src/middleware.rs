@@ -0,0 +1,148 @@
+use crate::*;
+use cross_messages::*;
+use std::net::SocketAddr;
+
+/// What a layer decided to do with a message.
+pub enum Flow {
+    /// Pass the, possibly modified, message on to the next layer.
+    Continue(Message),
+    /// Drop the message and send the error back to the sender.
+    Reject(ErrorReply),
+    /// Drop the message silently, e.g. because the layer handled it itself.
+    Stop,
+    /// Drop the message and close the connection.
+    Disconnect,
+}
+
+/// The connection a message was received on, or is about to be sent to.
+pub struct ConnInfo<'a> {
+    pub addr: SocketAddr,
+    pub id: &'a ID,
+    pub scope: &'a Scope,
+    pub register: &'a Register,
+    pub broadcast: &'a broadcast::Sender<Message>,
+}
+
+/// Wraps the message handling of every connection.
+/// Layers see all messages, including the ones relayed between devices.
+#[async_trait::async_trait]
+pub trait Layer: Send + Sync {
+    async fn on_message(&self, conn: &ConnInfo<'_>, msg: Message) -> Flow;
+
+    async fn on_outgoing(&self, _conn: &ConnInfo<'_>, msg: Message) -> Flow {
+        Flow::Continue(msg)
+    }
+
+    async fn on_disconnect(&self, _conn: &ConnInfo<'_>) {}
+
+    /// Largest frame the layer lets a connection send, larger ones are skipped unread.
+    fn max_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    /// A frame over the limit was skipped. Only `Reject` and `Disconnect` have an effect.
+    async fn on_oversized(&self, _conn: &ConnInfo<'_>, _frame: &FrameTooLarge) -> Flow {
+        Flow::Stop
+    }
+}
+
+#[async_trait::async_trait]
+impl<L> Layer for Arc<L>
+where
+    L: Layer + ?Sized,
+{
+    async fn on_message(&self, conn: &ConnInfo<'_>, msg: Message) -> Flow {
+        (**self).on_message(conn, msg).await
+    }
+
+    async fn on_outgoing(&self, conn: &ConnInfo<'_>, msg: Message) -> Flow {
+        (**self).on_outgoing(conn, msg).await
+    }
+
+    async fn on_disconnect(&self, conn: &ConnInfo<'_>) {
+        (**self).on_disconnect(conn).await
+    }
+
+    fn max_frame_len(&self) -> Option<usize> {
+        (**self).max_frame_len()
+    }
+
+    async fn on_oversized(&self, conn: &ConnInfo<'_>, frame: &FrameTooLarge) -> Flow {
+        (**self).on_oversized(conn, frame).await
+    }
+}
+
+/// Layers run in the order they were pushed.
+#[derive(Clone, Default)]
+pub struct Stack {
+    layers: Vec<Arc<dyn Layer>>,
+}
+
+impl Stack {
+    pub fn push(&mut self, layer: impl Layer + 'static) {
+        self.layers.push(Arc::new(layer));
+    }
+
+    pub async fn on_message(&self, conn: &ConnInfo<'_>, mut msg: Message) -> Flow {
+        for layer in &self.layers {
+            msg = match layer.on_message(conn, msg).await {
+                Flow::Continue(msg) => msg,
+                flow => return flow,
+            };
+        }
+
+        Flow::Continue(msg)
+    }
+
+    pub async fn on_outgoing(&self, conn: &ConnInfo<'_>, mut msg: Message) -> Flow {
+        for layer in &self.layers {
+            msg = match layer.on_outgoing(conn, msg).await {
+                Flow::Continue(msg) => msg,
+                flow => return flow,
+            };
+        }
+
+        Flow::Continue(msg)
+    }
+
+    pub async fn on_disconnect(&self, conn: &ConnInfo<'_>) {
+        for layer in &self.layers {
+            layer.on_disconnect(conn).await;
+        }
+    }
+
+    /// The smallest limit of all layers.
+    pub fn max_frame_len(&self) -> Option<usize> {
+        self.layers.iter().filter_map(|l| l.max_frame_len()).min()
+    }
+
+    /// Defaults to rejecting the frame as too large.
+    pub async fn on_oversized(&self, conn: &ConnInfo<'_>, frame: &FrameTooLarge) -> Flow {
+        for layer in &self.layers {
+            let flow = layer.on_oversized(conn, frame).await;
+            if matches!(flow, Flow::Reject(_) | Flow::Disconnect) {
+                return flow;
+            }
+        }
+
+        Flow::Reject(ErrorReply::TooLarge(frame.to_string()))
+    }
+}
+
+/// Logs a summary of every incoming message.
+pub struct LogLayer;
+
+#[async_trait::async_trait]
+impl Layer for LogLayer {
+    async fn on_message(&self, conn: &ConnInfo<'_>, msg: Message) -> Flow {
+        log::info!(
+            "{:?} from {} ({}) to {}, {} bytes",
+            msg.header.kind,
+            conn.id,
+            conn.addr,
+            msg.header.target,
+            msg.body.len()
+        );
+        Flow::Continue(msg)
+    }
+}
@@ -0,0 +1,75 @@
+use crate::Scope;
+use cross_messages::PairingCode;
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Unambiguous characters for codes which are typed in by hand.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// Short-lived one-time codes, which let a new device join the scope of
+/// the device that requested the code.
+pub struct Pairing {
+    codes: Mutex<HashMap<String, PendingPairing>>,
+    ttl: Duration,
+}
+
+struct PendingPairing {
+    scope: Scope,
+    expires: Instant,
+}
+
+impl Pairing {
+    pub fn new(ttl: Duration) -> Self {
+        Pairing {
+            codes: Mutex::default(),
+            ttl,
+        }
+    }
+
+    pub fn issue(&self, scope: Scope) -> PairingCode {
+        let mut codes = self.codes.lock().unwrap();
+        codes.retain(|_, pending| pending.expires > Instant::now());
+
+        let code = new_code();
+        codes.insert(
+            code.clone(),
+            PendingPairing {
+                scope,
+                expires: Instant::now() + self.ttl,
+            },
+        );
+
+        PairingCode {
+            code,
+            expires_in_secs: self.ttl.as_secs(),
+        }
+    }
+
+    /// Consumes `code`, returning the scope it was issued for.
+    pub fn redeem(&self, code: &str) -> Option<Scope> {
+        let code = code.trim().to_uppercase();
+        self.codes
+            .lock()
+            .unwrap()
+            .remove(&code)
+            .filter(|pending| pending.expires > Instant::now())
+            .map(|pending| pending.scope)
+    }
+}
+
+impl Default for Pairing {
+    fn default() -> Self {
+        Pairing::new(Duration::from_secs(300))
+    }
+}
+
+fn new_code() -> String {
+    let mut rng = rand::thread_rng();
+    let chars: String = (0..8)
+        .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+        .collect();
+
+    format!("{}-{}", &chars[..4], &chars[4..])
+}
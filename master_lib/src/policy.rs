@@ -0,0 +1,82 @@
+use crate::Device;
+use cross_messages::*;
+use serde::{Deserialize, Serialize};
+
+/// Decides which device may send which kind of message to whom.
+/// Rules are checked in order, the first matching rule wins.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Policy {
+    #[serde(default)]
+    pub default: Action,
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// Unset fields match everything, `"*"` does as well.
+/// `from` and `to` match device names, which are only verified with accounts.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Rule {
+    #[serde(default)]
+    pub kinds: Vec<MessageKind>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub group: Option<String>,
+    pub user: Option<String>,
+    pub action: Action,
+}
+
+impl Policy {
+    pub fn check(&self, kind: MessageKind, from: &Device, to: &Device) -> Action {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(kind, from, to))
+            .map(|rule| rule.action)
+            .unwrap_or(self.default)
+    }
+}
+
+impl Rule {
+    /// Whether the rule tells devices apart by name.
+    pub fn names_devices(&self) -> bool {
+        [&self.from, &self.to]
+            .iter()
+            .any(|pattern| pattern.as_deref().is_some_and(|pattern| pattern != "*"))
+    }
+
+    fn matches(&self, kind: MessageKind, from: &Device, to: &Device) -> bool {
+        (self.kinds.is_empty() || self.kinds.contains(&kind))
+            && matches_pattern(&self.from, Some(&from.name))
+            && matches_pattern(&self.to, Some(&to.name))
+            && matches_pattern(&self.group, Some(&from.scope.group))
+            && matches_pattern(&self.user, from.scope.user.as_ref())
+    }
+}
+
+fn matches_pattern(pattern: &Option<String>, value: Option<&String>) -> bool {
+    match pattern.as_deref() {
+        None | Some("*") => true,
+        Some(pattern) => value.is_some_and(|value| value == pattern),
+    }
+}
+
+/// Checks that a message carries the ID of its connection, which is
+/// `ID::Unregistered` until the device registered. Otherwise replies could
+/// be directed at another device.
+pub fn check_origin(msg: &Message, id: &ID) -> Result<(), ErrorReply> {
+    if &msg.tail.from == id {
+        return Ok(());
+    }
+
+    Err(ErrorReply::Forbidden(format!(
+        "Message from {:?} claims to be from {:?}",
+        id, msg.tail.from
+    )))
+}
@@ -0,0 +1,253 @@
+use crate::middleware::{ConnInfo, Flow, Layer};
+use cross_messages::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::Instant;
+
+/// Room for the JSON around the body of a message, e.g. its header and tail.
+const FRAME_OVERHEAD: usize = 4 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RateLimitConfig {
+    /// Messages refilled into the bucket of a connection per second.
+    #[serde(default = "default_messages_per_sec")]
+    pub messages_per_sec: f64,
+    /// Messages a connection may send at once after being idle.
+    #[serde(default = "default_burst")]
+    pub burst: u32,
+    /// Also bounds the frames a connection may send, larger ones are skipped unread.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// Violations in a row after which the connection is closed.
+    #[serde(default = "default_max_violations")]
+    pub max_violations: u32,
+}
+
+fn default_messages_per_sec() -> f64 {
+    10.0
+}
+
+fn default_burst() -> u32 {
+    20
+}
+
+fn default_max_body_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_max_violations() -> u32 {
+    10
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            messages_per_sec: default_messages_per_sec(),
+            burst: default_burst(),
+            max_body_bytes: default_max_body_bytes(),
+            max_violations: default_max_violations(),
+        }
+    }
+}
+
+/// Counters of a single connection.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RateLimitStats {
+    pub id: Option<ID>,
+    pub allowed: u64,
+    pub rate_limited: u64,
+    pub too_large: u64,
+    pub disconnects: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    violations: u32,
+    stats: RateLimitStats,
+}
+
+/// Token bucket rate limit and body size quota per connection.
+pub struct RateLimitLayer {
+    enabled: AtomicBool,
+    config: RwLock<RateLimitConfig>,
+    buckets: Mutex<HashMap<SocketAddr, Bucket>>,
+    totals: Mutex<RateLimitStats>,
+}
+
+impl RateLimitLayer {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimitLayer {
+            enabled: AtomicBool::new(true),
+            config: RwLock::new(config),
+            buckets: Mutex::default(),
+            totals: Mutex::default(),
+        }
+    }
+
+    pub fn set_config(&self, config: RateLimitConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    /// A disabled layer lets every message pass, without counting it.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Counters of all open connections.
+    pub fn stats(&self) -> Vec<(SocketAddr, RateLimitStats)> {
+        self.buckets
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, bucket)| (*addr, bucket.stats.clone()))
+            .collect()
+    }
+
+    /// Counters summed over all connections since startup.
+    pub fn totals(&self) -> RateLimitStats {
+        self.totals.lock().unwrap().clone()
+    }
+
+    fn bucket<'a>(
+        config: &RateLimitConfig,
+        buckets: &'a mut HashMap<SocketAddr, Bucket>,
+        conn: &ConnInfo<'_>,
+    ) -> &'a mut Bucket {
+        let bucket = buckets.entry(conn.addr).or_insert_with(|| Bucket {
+            tokens: config.burst as f64,
+            last_refill: Instant::now(),
+            violations: 0,
+            stats: RateLimitStats::default(),
+        });
+        bucket.stats.id = Some(conn.id.clone());
+        bucket
+    }
+
+    fn check(&self, conn: &ConnInfo<'_>, msg: &Message) -> Result<(), ErrorReply> {
+        let config = self.config.read().unwrap();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = Self::bucket(&config, &mut buckets, conn);
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens =
+            (bucket.tokens + elapsed * config.messages_per_sec).min(config.burst as f64);
+        bucket.last_refill = now;
+
+        let mut totals = self.totals.lock().unwrap();
+        if msg.body.len() > config.max_body_bytes {
+            bucket.stats.too_large += 1;
+            totals.too_large += 1;
+            return Err(ErrorReply::TooLarge(format!(
+                "Body of {} bytes exceeds limit of {}",
+                msg.body.len(),
+                config.max_body_bytes
+            )));
+        }
+
+        if bucket.tokens < 1.0 {
+            bucket.stats.rate_limited += 1;
+            totals.rate_limited += 1;
+            return Err(ErrorReply::RateLimited(format!(
+                "More than {} messages per second",
+                config.messages_per_sec
+            )));
+        }
+
+        bucket.tokens -= 1.0;
+        bucket.violations = 0;
+        bucket.stats.allowed += 1;
+        totals.allowed += 1;
+        Ok(())
+    }
+
+    /// Counts a frame that was skipped before it could be checked.
+    fn oversized(&self, conn: &ConnInfo<'_>) {
+        let config = self.config.read().unwrap();
+        let mut buckets = self.buckets.lock().unwrap();
+        Self::bucket(&config, &mut buckets, conn).stats.too_large += 1;
+        self.totals.lock().unwrap().too_large += 1;
+    }
+
+    /// Returns true if the connection exceeded the allowed violations.
+    fn violated(&self, conn: &ConnInfo<'_>) -> bool {
+        let max_violations = self.config.read().unwrap().max_violations;
+        let mut buckets = self.buckets.lock().unwrap();
+        let Some(bucket) = buckets.get_mut(&conn.addr) else {
+            return false;
+        };
+
+        bucket.violations += 1;
+        if bucket.violations <= max_violations {
+            return false;
+        }
+
+        bucket.stats.disconnects += 1;
+        self.totals.lock().unwrap().disconnects += 1;
+        true
+    }
+
+    /// Rejects the message, or closes the connection after too many violations.
+    fn violation(&self, conn: &ConnInfo<'_>, e: ErrorReply) -> Flow {
+        if !self.violated(conn) {
+            return Flow::Reject(e);
+        }
+
+        log::warn!(
+            "Disconnecting {} ({}) after repeated limit violations",
+            conn.id,
+            conn.addr
+        );
+        Flow::Disconnect
+    }
+}
+
+#[async_trait::async_trait]
+impl Layer for RateLimitLayer {
+    async fn on_message(&self, conn: &ConnInfo<'_>, msg: Message) -> Flow {
+        if !self.is_enabled() {
+            return Flow::Continue(msg);
+        }
+
+        match self.check(conn, &msg) {
+            Ok(()) => Flow::Continue(msg),
+            Err(e) => self.violation(conn, e),
+        }
+    }
+
+    fn max_frame_len(&self) -> Option<usize> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let max_body_bytes = self.config.read().unwrap().max_body_bytes;
+        Some(max_body_bytes.saturating_add(FRAME_OVERHEAD))
+    }
+
+    async fn on_oversized(&self, conn: &ConnInfo<'_>, frame: &FrameTooLarge) -> Flow {
+        if !self.is_enabled() {
+            return Flow::Stop;
+        }
+
+        self.oversized(conn);
+        self.violation(conn, ErrorReply::TooLarge(frame.to_string()))
+    }
+
+    async fn on_disconnect(&self, conn: &ConnInfo<'_>) {
+        let Some(bucket) = self.buckets.lock().unwrap().remove(&conn.addr) else {
+            return;
+        };
+
+        if bucket.stats.rate_limited > 0 || bucket.stats.too_large > 0 {
+            log::warn!("Limits of {} ({}): {:?}", conn.id, conn.addr, bucket.stats);
+        }
+    }
+}
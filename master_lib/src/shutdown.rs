@@ -0,0 +1,68 @@
+use cross_messages::ShutdownNotice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+#[derive(Clone, Debug)]
+pub struct ShutdownOptions {
+    /// Sent to every connected device.
+    pub notice: ShutdownNotice,
+    /// Time given to in-flight relays and connections to finish.
+    pub deadline: Duration,
+}
+
+impl Default for ShutdownOptions {
+    fn default() -> Self {
+        ShutdownOptions {
+            notice: ShutdownNotice::default(),
+            deadline: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Stops a running `MasterServer`, after which `run` returns.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    pub(crate) request: Arc<watch::Sender<Option<ShutdownOptions>>>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self, options: ShutdownOptions) {
+        log::info!("Shutdown requested");
+        self.request.send_replace(Some(options));
+    }
+}
+
+/// Where a connection is in the shutdown of its Master.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Phase {
+    Running,
+    /// Stop reading from the device, but keep relaying to it.
+    Stopping,
+    /// Relay what is left, send the notice and close.
+    Closing(ShutdownNotice),
+}
+
+/// Counts messages which were received but not relayed yet.
+#[derive(Clone, Default)]
+pub(crate) struct InFlight(Arc<AtomicUsize>);
+
+pub(crate) struct InFlightGuard(Arc<AtomicUsize>);
+
+impl InFlight {
+    pub(crate) fn start(&self) -> InFlightGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self.0.clone())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.load(Ordering::SeqCst) == 0
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,288 @@
+use argon2::password_hash::{PasswordHash, SaltString};
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+use rand::{rngs::OsRng, RngCore};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Sqlite backed store of user accounts and the devices registered by them.
+/// Clones share the same connection.
+#[derive(Clone)]
+pub struct UserStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct User {
+    pub name: String,
+    pub admin: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct StoredDevice {
+    pub user: String,
+    pub name: String,
+    pub revoked: bool,
+}
+
+/// Whether a device may log in with the password of its user.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeviceAccess {
+    Allowed,
+    /// Neither paired nor the first device of its user.
+    Unknown,
+    Revoked,
+}
+
+impl UserStore {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::with(Connection::open(path)?)
+    }
+
+    pub fn in_memory() -> anyhow::Result<Self> {
+        Self::with(Connection::open_in_memory()?)
+    }
+
+    fn with(conn: Connection) -> anyhow::Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS users (
+                name TEXT PRIMARY KEY,
+                password_hash TEXT NOT NULL,
+                admin INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS devices (
+                user TEXT NOT NULL REFERENCES users(name) ON DELETE CASCADE,
+                name TEXT NOT NULL,
+                revoked INTEGER NOT NULL DEFAULT 0,
+                token_hash TEXT,
+                PRIMARY KEY (user, name)
+            );",
+        )?;
+
+        Ok(UserStore {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` on the blocking thread pool, as password hashing and sqlite
+    /// would otherwise stall the async executor.
+    pub async fn blocking<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&UserStore) -> anyhow::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || f(&store)).await?
+    }
+
+    pub fn add_user(&self, name: &str, password: &str, admin: bool) -> anyhow::Result<()> {
+        let hash = hash_password(password)?;
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO users (name, password_hash, admin) VALUES (?1, ?2, ?3)
+             ON CONFLICT(name) DO UPDATE SET password_hash = ?2, admin = ?3",
+            params![name, hash, admin],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_user(&self, name: &str) -> anyhow::Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM devices WHERE user = ?1", params![name])?;
+        Ok(conn.execute("DELETE FROM users WHERE name = ?1", params![name])? > 0)
+    }
+
+    pub fn user(&self, name: &str) -> anyhow::Result<Option<User>> {
+        Ok(self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT name, admin FROM users WHERE name = ?1",
+                params![name],
+                |row| {
+                    Ok(User {
+                        name: row.get(0)?,
+                        admin: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    pub fn users(&self) -> anyhow::Result<Vec<User>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT name, admin FROM users ORDER BY name")?;
+        let users = stmt
+            .query_map([], |row| {
+                Ok(User {
+                    name: row.get(0)?,
+                    admin: row.get(1)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(users)
+    }
+
+    /// Returns the user if `password` matches the stored hash.
+    pub fn authenticate(&self, name: &str, password: &str) -> anyhow::Result<Option<User>> {
+        let row: Option<(String, bool)> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT password_hash, admin FROM users WHERE name = ?1",
+                params![name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        let Some((hash, admin)) = row else {
+            return Ok(None);
+        };
+
+        let parsed = PasswordHash::new(&hash).map_err(|e| anyhow::anyhow!("{}", e))?;
+        if Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_err()
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(User {
+            name: name.to_string(),
+            admin,
+        }))
+    }
+
+    /// Checks `device` of `user` for a password login. Only the first device
+    /// of a user is added this way, later ones have to be paired, so that a
+    /// revoked device can't come back under another name.
+    pub fn login_device(&self, user: &str, device: &str) -> anyhow::Result<DeviceAccess> {
+        let conn = self.conn.lock().unwrap();
+        match revoked(&conn, user, device)? {
+            Some(true) => return Ok(DeviceAccess::Revoked),
+            Some(false) => return Ok(DeviceAccess::Allowed),
+            None => {}
+        }
+
+        let known: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM devices WHERE user = ?1",
+            params![user],
+            |row| row.get(0),
+        )?;
+        if known > 0 {
+            return Ok(DeviceAccess::Unknown);
+        }
+
+        conn.execute(
+            "INSERT INTO devices (user, name) VALUES (?1, ?2)",
+            params![user, device],
+        )?;
+        Ok(DeviceAccess::Allowed)
+    }
+
+    /// Creates a new token for `device` of `user`, replacing any previous one
+    /// and adding the device if it's new. Returns `None` if it was revoked.
+    pub fn issue_device_token(&self, user: &str, device: &str) -> anyhow::Result<Option<String>> {
+        let conn = self.conn.lock().unwrap();
+        if revoked(&conn, user, device)? == Some(true) {
+            return Ok(None);
+        }
+
+        let token = random_token();
+        conn.execute(
+            "INSERT INTO devices (user, name, token_hash) VALUES (?1, ?2, ?3)
+             ON CONFLICT(user, name) DO UPDATE SET token_hash = ?3",
+            params![user, device, hash_password(&token)?],
+        )?;
+        Ok(Some(token))
+    }
+
+    /// Returns the user if `token` was issued to the not revoked `device`.
+    pub fn authenticate_device(
+        &self,
+        user: &str,
+        device: &str,
+        token: &str,
+    ) -> anyhow::Result<Option<User>> {
+        let hash: Option<Option<String>> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT token_hash FROM devices WHERE user = ?1 AND name = ?2 AND revoked = 0",
+                params![user, device],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let Some(Some(hash)) = hash else {
+            return Ok(None);
+        };
+
+        let parsed = PasswordHash::new(&hash).map_err(|e| anyhow::anyhow!("{}", e))?;
+        if Argon2::default()
+            .verify_password(token.as_bytes(), &parsed)
+            .is_err()
+        {
+            return Ok(None);
+        }
+
+        self.user(user)
+    }
+
+    pub fn devices(&self, user: &str) -> anyhow::Result<Vec<StoredDevice>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt =
+            conn.prepare("SELECT user, name, revoked FROM devices WHERE user = ?1 ORDER BY name")?;
+        let devices = stmt
+            .query_map(params![user], |row| {
+                Ok(StoredDevice {
+                    user: row.get(0)?,
+                    name: row.get(1)?,
+                    revoked: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(devices)
+    }
+
+    pub fn revoke_device(&self, user: &str, device: &str) -> anyhow::Result<bool> {
+        Ok(self.conn.lock().unwrap().execute(
+            "UPDATE devices SET revoked = 1 WHERE user = ?1 AND name = ?2",
+            params![user, device],
+        )? > 0)
+    }
+
+    pub fn rename_device(&self, user: &str, device: &str, new_name: &str) -> anyhow::Result<bool> {
+        Ok(self.conn.lock().unwrap().execute(
+            "UPDATE devices SET name = ?3 WHERE user = ?1 AND name = ?2",
+            params![user, device, new_name],
+        )? > 0)
+    }
+}
+
+/// `None` if `device` of `user` is unknown.
+fn revoked(conn: &Connection, user: &str, device: &str) -> rusqlite::Result<Option<bool>> {
+    conn.query_row(
+        "SELECT revoked FROM devices WHERE user = ?1 AND name = ?2",
+        params![user, device],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+fn hash_password(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!("{}", e))?
+        .to_string())
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,137 @@
+use client_lib::{CrossClient, CrossHandle};
+use cross_messages::transport::Memory;
+use cross_messages::{ErrorReply, MessageKind, RegisterRequest, ID};
+use master_lib::handler::DefaultMessageHandler;
+use master_lib::ratelimit::{RateLimitConfig, RateLimitLayer};
+use master_lib::MasterServer;
+use std::time::Duration;
+
+async fn start_master(transport: &Memory) {
+    let mut server = MasterServer::bind(transport, "master", DefaultMessageHandler)
+        .await
+        .unwrap();
+    tokio::spawn(async move { server.run().await });
+}
+
+async fn register(transport: &Memory) -> CrossHandle {
+    let (mut client, handle) = CrossClient::connect(transport, "master")
+        .await
+        .unwrap()
+        .register(RegisterRequest::default())
+        .await
+        .unwrap();
+    tokio::spawn(async move { client.run().await });
+    handle
+}
+
+/// Waits for the next message of `kind`, skipping the others.
+async fn recv_kind(handle: &mut CrossHandle, kind: MessageKind) -> cross_messages::Message {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let msg = handle.recv().await.expect("connection closed");
+            if msg.header.kind == kind {
+                return msg;
+            }
+        }
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn registers() {
+    let transport = Memory::default();
+    start_master(&transport).await;
+
+    let handle = register(&transport).await;
+    assert!(matches!(handle.registered_id, ID::Slave(_)));
+}
+
+#[tokio::test]
+async fn relays_between_devices() {
+    let transport = Memory::default();
+    start_master(&transport).await;
+
+    let mut a = register(&transport).await;
+    let b = register(&transport).await;
+    recv_kind(&mut a, MessageKind::NewRegDevice).await;
+
+    b.send(
+        a.registered_id.clone(),
+        MessageKind::Clipboard,
+        "hello".to_string(),
+    )
+    .await
+    .unwrap();
+    let msg = recv_kind(&mut a, MessageKind::Clipboard).await;
+    assert_eq!(msg.body, "hello");
+    assert_eq!(msg.tail.from, b.registered_id);
+}
+
+#[tokio::test]
+async fn lists_registered_devices() {
+    let transport = Memory::default();
+    start_master(&transport).await;
+
+    let mut a = register(&transport).await;
+    let b = register(&transport).await;
+    recv_kind(&mut a, MessageKind::NewRegDevice).await;
+
+    let reply = a
+        .request(MessageKind::GetRegDevices, String::new())
+        .await
+        .unwrap();
+    let devices: Vec<ID> = serde_json::from_str(&reply.body).unwrap();
+    assert_eq!(devices, vec![b.registered_id.clone()]);
+}
+
+#[tokio::test]
+async fn rejects_oversized_frames() {
+    let transport = Memory::default();
+    let mut server = MasterServer::bind(&transport, "master", DefaultMessageHandler)
+        .await
+        .unwrap();
+    server.add_layer(RateLimitLayer::new(RateLimitConfig {
+        max_body_bytes: 1024,
+        ..RateLimitConfig::default()
+    }));
+    tokio::spawn(async move { server.run().await });
+
+    let mut a = register(&transport).await;
+    a.send(ID::Master, MessageKind::Clipboard, "x".repeat(64 * 1024))
+        .await
+        .unwrap();
+    let reply = recv_kind(&mut a, MessageKind::Error).await;
+    assert!(matches!(
+        ErrorReply::from_message(&reply).unwrap(),
+        ErrorReply::TooLarge(_)
+    ));
+
+    // The connection stays usable after the skipped frame
+    let reply = a
+        .request(MessageKind::GetRegDevices, String::new())
+        .await
+        .unwrap();
+    assert_eq!(reply.header.kind, MessageKind::Reply);
+}
+
+#[tokio::test]
+async fn notices_every_device() {
+    let transport = Memory::default();
+    let mut server = MasterServer::bind(&transport, "master", DefaultMessageHandler)
+        .await
+        .unwrap();
+    let master = server.handle();
+    tokio::spawn(async move { server.run().await });
+
+    let mut devices = Vec::new();
+    for _ in 0..20 {
+        devices.push(register(&transport).await);
+    }
+
+    assert_eq!(master.notice("maintenance").await, devices.len());
+    for device in &mut devices {
+        let msg = recv_kind(device, MessageKind::Notice).await;
+        assert_eq!(msg.body, "maintenance");
+    }
+}
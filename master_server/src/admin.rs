@@ -0,0 +1,140 @@
+use axum::extract::{Path, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use cross_messages::ID;
+use master_lib::control::{ConnectionInfo, MasterHandle};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+
+#[derive(Clone)]
+struct AdminState {
+    handle: MasterHandle,
+    token: Arc<str>,
+}
+
+#[derive(Deserialize)]
+struct Rename {
+    name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Notice {
+    message: String,
+}
+
+#[derive(Serialize)]
+struct Delivered {
+    delivered: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Draining {
+    draining: bool,
+}
+
+/// Serves the JSON admin API, every request needs `Authorization: Bearer <token>`.
+pub async fn serve(addr: String, token: String, handle: MasterHandle) -> anyhow::Result<()> {
+    let state = AdminState {
+        handle,
+        token: token.into(),
+    };
+
+    let app = Router::new()
+        .route("/devices", get(list_devices))
+        .route("/devices/{id}", delete(kick_device).patch(rename_device))
+        .route("/connections/{addr}", delete(kick_connection))
+        .route("/notice", post(notice))
+        .route("/drain", get(draining).post(set_draining))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            authorize,
+        ))
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    log::info!("Serving admin API on http://{}", addr);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn authorize(State(state): State<AdminState>, req: Request, next: Next) -> Response {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        // Constant time, so the token can't be guessed byte by byte
+        .is_some_and(|token| token.as_bytes().ct_eq(state.token.as_bytes()).into());
+
+    if !authorized {
+        log::warn!("Unauthorized admin request to {}", req.uri());
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    next.run(req).await
+}
+
+async fn list_devices(State(state): State<AdminState>) -> Json<Vec<ConnectionInfo>> {
+    Json(state.handle.connections().await)
+}
+
+async fn kick_device(State(state): State<AdminState>, Path(id): Path<String>) -> StatusCode {
+    let Ok(id) = id.parse::<ID>() else {
+        return StatusCode::BAD_REQUEST;
+    };
+
+    match state.handle.kick(&id).await {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn rename_device(
+    State(state): State<AdminState>,
+    Path(id): Path<String>,
+    Json(rename): Json<Rename>,
+) -> StatusCode {
+    let Ok(id) = id.parse::<ID>() else {
+        return StatusCode::BAD_REQUEST;
+    };
+
+    match state.handle.rename(&id, &rename.name).await {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn kick_connection(State(state): State<AdminState>, Path(addr): Path<String>) -> StatusCode {
+    let Ok(addr) = addr.parse() else {
+        return StatusCode::BAD_REQUEST;
+    };
+
+    match state.handle.kick_addr(&addr).await {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn notice(State(state): State<AdminState>, Json(notice): Json<Notice>) -> Json<Delivered> {
+    Json(Delivered {
+        delivered: state.handle.notice(&notice.message).await,
+    })
+}
+
+async fn draining(State(state): State<AdminState>) -> Json<Draining> {
+    Json(Draining {
+        draining: state.handle.is_draining(),
+    })
+}
+
+async fn set_draining(
+    State(state): State<AdminState>,
+    Json(draining): Json<Draining>,
+) -> Json<Draining> {
+    state.handle.set_draining(draining.draining);
+    Json(draining)
+}
@@ -0,0 +1,147 @@
+use master_lib::control::ConnectionInfo;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// A command sent over the control socket, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlRequest {
+    ListDevices,
+    Kick { id: String },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlReply {
+    Devices { devices: Vec<ConnectionInfo> },
+    Kicked { found: bool },
+    Error { message: String },
+}
+
+#[cfg(unix)]
+pub use unix::*;
+
+#[cfg(unix)]
+mod unix {
+    use super::*;
+    use cross_messages::ID;
+    use master_lib::control::MasterHandle;
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{UnixListener, UnixStream};
+
+    /// Accepts management commands on the Unix socket at `path`.
+    /// The socket is only accessible by the user running the Master.
+    pub async fn serve(path: &Path, handle: MasterHandle) -> anyhow::Result<()> {
+        if path.exists() {
+            if UnixStream::connect(path).await.is_ok() {
+                anyhow::bail!("{} is used by another running Master", path.display());
+            }
+            std::fs::remove_file(path)?;
+        }
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let listener = bind_private(path)?;
+        log::info!("Listening for control commands on {}", path.display());
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let handle = handle.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_connection(stream, handle).await {
+                    log::warn!("Control connection failed due to {}", e);
+                }
+            });
+        }
+    }
+
+    /// Binds the socket in a directory only the current user can enter and
+    /// moves it to `path` once it's 0600, so it's never reachable by others.
+    fn bind_private(path: &Path) -> anyhow::Result<UnixListener> {
+        let dir = path.with_extension(format!("{}.tmp", std::process::id()));
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+        let bound = dir.join("socket");
+        let res = UnixListener::bind(&bound)
+            .and_then(|listener| {
+                std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(0o600))?;
+                std::fs::rename(&bound, path)?;
+                Ok(listener)
+            })
+            .map_err(Into::into);
+
+        let _ = std::fs::remove_dir_all(&dir);
+        res
+    }
+
+    async fn serve_connection(stream: UnixStream, handle: MasterHandle) -> anyhow::Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            let reply = match serde_json::from_str(&line) {
+                Ok(req) => execute(req, &handle).await,
+                Err(e) => ControlReply::Error {
+                    message: format!("Invalid command: {}", e),
+                },
+            };
+
+            let mut reply = serde_json::to_string(&reply)?;
+            reply.push('\n');
+            write.write_all(reply.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn execute(req: ControlRequest, handle: &MasterHandle) -> ControlReply {
+        log::debug!("Control command {:?}", req);
+        match req {
+            ControlRequest::ListDevices => ControlReply::Devices {
+                devices: handle.connections().await,
+            },
+            ControlRequest::Kick { id } => match id.parse::<ID>() {
+                Ok(id) => ControlReply::Kicked {
+                    found: handle.kick(&id).await,
+                },
+                Err(e) => ControlReply::Error {
+                    message: format!("Invalid device id '{}': {}", id, e),
+                },
+            },
+        }
+    }
+
+    /// Sends a single command to the Master listening on `path`.
+    pub async fn request(path: &Path, req: &ControlRequest) -> anyhow::Result<ControlReply> {
+        let stream = UnixStream::connect(path).await.map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to connect to {}, is the Master running? ({})",
+                path.display(),
+                e
+            )
+        })?;
+        let (read, mut write) = stream.into_split();
+
+        let mut line = serde_json::to_string(req)?;
+        line.push('\n');
+        write.write_all(line.as_bytes()).await?;
+
+        match BufReader::new(read).lines().next_line().await? {
+            Some(line) => Ok(serde_json::from_str(&line)?),
+            None => anyhow::bail!("Master closed the control connection"),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn serve(_path: &Path, _handle: master_lib::control::MasterHandle) -> anyhow::Result<()> {
+    anyhow::bail!("The control socket is only supported on Unix")
+}
+
+#[cfg(not(unix))]
+pub async fn request(_path: &Path, _req: &ControlRequest) -> anyhow::Result<ControlReply> {
+    anyhow::bail!("The control socket is only supported on Unix")
+}
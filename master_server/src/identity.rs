@@ -0,0 +1,52 @@
+use cross_messages::quic::Certificate;
+use rand::RngCore;
+use std::path::Path;
+
+/// Loads the identity key at `path` and the certificate next to it,
+/// creating both on first start. Devices pin the fingerprint of the certificate.
+pub fn load_certificate(path: &Path) -> anyhow::Result<Certificate> {
+    let seed = match std::fs::read(path) {
+        Ok(seed) => seed,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            log::info!("Creating new identity key at {}", path.display());
+            let mut seed = vec![0u8; 32];
+            rand::thread_rng().fill_bytes(&mut seed);
+
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            write_private(path, &seed)?;
+            seed
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    // Kept, so the fingerprint stays the same across restarts
+    let cert_path = path.with_extension("crt");
+    match std::fs::read(&cert_path) {
+        Ok(cert) => {
+            let generated = Certificate::from_seed(&seed)?;
+            Ok(Certificate {
+                cert,
+                key: generated.key,
+            })
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            log::info!("Creating new certificate at {}", cert_path.display());
+            let certificate = Certificate::from_seed(&seed)?;
+            std::fs::write(&cert_path, &certificate.cert)?;
+            Ok(certificate)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
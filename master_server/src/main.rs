@@ -1,37 +1,311 @@
+mod admin;
+mod control;
+mod identity;
 mod master_config;
+mod metrics;
+mod reload;
+
+use control::{ControlReply, ControlRequest};
+use cross_messages::quic::Quic;
+use cross_messages::transport::TransportConfig;
+use master_config::MasterConfig;
+
+#[derive(clap::Parser)]
+#[command(about = "Crosslive master server")]
+struct Cli {
+    /// Config file, defaults to `CROSSCONFIG` or the user config directory
+    #[arg(long, global = true)]
+    config: Option<String>,
+    /// Address devices connect to, overrides the config
+    #[arg(long, global = true)]
+    host_ip: Option<String>,
+    #[arg(long, global = true)]
+    host_port: Option<u16>,
+    /// One of error, warn, info, debug or trace
+    #[arg(long, global = true)]
+    log_level: Option<String>,
+    /// Overrides any config value, e.g. `--set rate_limit.burst=50`
+    #[arg(
+        long = "set",
+        value_name = "KEY=VALUE",
+        value_parser = crossconfig::parse_override,
+        global = true
+    )]
+    overrides: Vec<(String, String)>,
+    /// Print the config merged from file, `CROSSLIVE_*` env vars and flags
+    #[arg(long, global = true)]
+    print_config: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+impl Cli {
+    fn layers(&self) -> crossconfig::Layers {
+        let mut layers = crossconfig::Layers::new(self.config.clone(), master_config::FILE_NAME);
+        if let Some(host_ip) = &self.host_ip {
+            layers.set("host_ip", host_ip);
+        }
+        if let Some(host_port) = self.host_port {
+            layers.set("host_port", host_port);
+        }
+        if let Some(log_level) = &self.log_level {
+            layers.set("log_level", log_level);
+        }
+        layers.set_all(self.overrides.clone());
+        layers
+    }
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Run the server (default)
+    Run,
+    /// Manage the devices of the running server
+    Devices {
+        #[command(subcommand)]
+        command: DevicesCommand,
+    },
+    /// Inspect the config
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Print a commented config template
+    GenConfig {
+        /// Write the template to this file instead of stdout
+        #[arg(long)]
+        output: Option<String>,
+        /// Overwrite an existing file
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum DevicesCommand {
+    /// List the connected devices
+    List {
+        #[arg(long)]
+        json: bool,
+    },
+    /// Disconnect a device
+    Kick { id: String },
+}
+
+#[derive(clap::Subcommand)]
+enum ConfigCommand {
+    /// Load the config and report problems
+    Check,
+}
 
 #[tokio::main]
 async fn main() {
-    crosslogging::init_fern_logger().unwrap();
+    let cli = <Cli as clap::Parser>::parse();
+    let layers = cli.layers();
 
-    let config = match master_config::MasterConfig::get() {
-        Ok(c) => c,
-        Err(e) => {
-            log::error!("Failed to load Config due to {}", e);
-            std::process::exit(1)
+    let res = match cli.command.unwrap_or(Command::Run) {
+        _ if cli.print_config => print_config(&layers),
+        Command::Run => {
+            crosslogging::init_fern_logger().unwrap();
+            let config = match MasterConfig::load(&layers) {
+                Ok(c) => c,
+                Err(e) => {
+                    log::error!("Failed to load Config due to {}", e);
+                    std::process::exit(1)
+                }
+            };
+            run(layers, config).await;
+            Ok(())
         }
+        Command::Devices { command } => devices(&layers, command).await,
+        Command::Config {
+            command: ConfigCommand::Check,
+        } => check_config(&layers),
+        Command::GenConfig { output, force } => gen_config(output, force),
     };
 
+    if let Err(e) = res {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// `--log-level` goes over `LOGLEVEL`, which goes over the config.
+fn apply_log_level(layers: &crossconfig::Layers, config: &MasterConfig) -> anyhow::Result<()> {
+    match layers.get("log_level") {
+        Some(level) => crosslogging::set_level(level),
+        None => crosslogging::set_config_level(config.log_level.as_deref()),
+    }
+}
+
+async fn run(layers: crossconfig::Layers, config: MasterConfig) {
     log::info!("Loaded config");
+    if let Err(e) = apply_log_level(&layers, &config) {
+        log::warn!("Keeping the default log level, {}", e);
+    }
 
     log::info!(
-        "Creating new Server Instance with IpV4 Address {}",
-        config.master_addr()
+        "Creating new Server Instance with IpV4 Address {} over {:?}",
+        config.master_addr(),
+        config.transport
     );
 
-    let mut server = match master_lib::MasterServer::new(
-        config.master_addr(),
+    let certificate = match config.identity_path() {
+        Some(path) => match identity::load_certificate(&path) {
+            Ok(certificate) => Some(certificate),
+            Err(e) => {
+                log::warn!("Failed to load identity key due to {}", e);
+                None
+            }
+        },
+        None => {
+            log::warn!("No path for the identity key, running without fingerprint");
+            None
+        }
+    };
+
+    let transport = TransportConfig {
+        kind: config.transport,
+        quic: certificate
+            .clone()
+            .map(Quic::with_certificate)
+            .unwrap_or_default(),
+    };
+    let mut server = match master_lib::MasterServer::bind(
+        &transport,
+        &config.master_addr(),
         master_lib::handler::DefaultMessageHandler,
     )
     .await
     {
-        Ok(s) => s,
+        Ok(server) => server,
         Err(e) => {
             log::error!("Failed to create Server Instance due to {}", e);
             std::process::exit(2);
         }
     };
 
+    if let Some(accounts) = &config.accounts {
+        match open_accounts(accounts) {
+            Ok(store) => server.set_accounts(std::sync::Arc::new(store)),
+            Err(e) => {
+                log::error!("Failed to open accounts database due to {}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if let Some(history) = &config.history {
+        match master_lib::history::SharedHistory::open(history.clone()) {
+            Ok(history) => server.set_history(std::sync::Arc::new(history)),
+            Err(e) => {
+                log::error!("Failed to open history database due to {}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    log::info!(
+        "Loaded policy with {} rules, default {:?}",
+        config.policy.rules.len(),
+        config.policy.default
+    );
+    server.set_policy(config.policy.clone());
+
+    server.set_pairing(master_lib::pairing::Pairing::new(
+        std::time::Duration::from_secs(config.pairing_ttl_secs),
+    ));
+
+    if let Some(certificate) = &certificate {
+        let fingerprint = certificate.fingerprint();
+        log::info!("Master fingerprint {}", fingerprint);
+        server.set_fingerprint(&fingerprint);
+    }
+
+    // Always in place, so limits can be added by reloading the config
+    let rate_limit = std::sync::Arc::new(master_lib::ratelimit::RateLimitLayer::new(
+        config.rate_limit.clone().unwrap_or_default(),
+    ));
+    rate_limit.set_enabled(config.rate_limit.is_some());
+    if let Some(limits) = &config.rate_limit {
+        log::info!("Limiting connections to {:?}", limits);
+    }
+    server.add_layer(rate_limit.clone());
+    tokio::spawn(log_rate_limits(rate_limit.clone()));
+
+    if let Some(metrics) = &config.metrics {
+        let addr = metrics.addr();
+        let metrics = server.metrics();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr, metrics).await {
+                log::error!("Metrics endpoint failed due to {}", e);
+            }
+        });
+    }
+
+    if let Some(admin) = &config.admin {
+        let addr = admin.addr();
+        let token = admin.token.clone();
+        let handle = server.handle();
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(addr, token, handle).await {
+                log::error!("Admin API failed due to {}", e);
+            }
+        });
+    }
+
+    if let Some(cluster) = &config.cluster {
+        log::info!(
+            "Joining cluster of {} nodes as '{}'",
+            cluster.nodes.len(),
+            cluster.name
+        );
+    }
+    if let Some(federation) = config.federation() {
+        let federation = server.federation(federation);
+        tokio::spawn(async move {
+            if let Err(e) = federation.run().await {
+                log::error!("Federation failed due to {}", e);
+            }
+        });
+    }
+
+    let socket = config.control_socket_path();
+    let handle = server.handle();
+    tokio::spawn(async move {
+        if let Err(e) = control::serve(&socket, handle).await {
+            log::error!("Control socket failed due to {}", e);
+        }
+    });
+
+    let shutdown = server.shutdown_handle();
+    let options = master_lib::shutdown::ShutdownOptions {
+        notice: cross_messages::ShutdownNotice {
+            reason: Some("Master stopped".to_string()),
+            reconnect_after_secs: config.shutdown.reconnect_after_secs,
+        },
+        deadline: std::time::Duration::from_secs(config.shutdown.deadline_secs),
+    };
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        log::info!("Shutting down, stop again to exit immediately");
+        shutdown.shutdown(options);
+
+        shutdown_signal().await;
+        log::warn!("Exiting without waiting for connections");
+        std::process::exit(130);
+    });
+
+    let watcher = layers.file().map(crossconfig::ConfigWatcher::new);
+    match watcher {
+        Some(Ok(watcher)) => {
+            let reloader = reload::Reloader::new(layers, config, server.handle(), rate_limit);
+            tokio::spawn(reloader.run(watcher));
+        }
+        Some(Err(e)) => log::warn!("Failed to watch the config for changes due to {}", e),
+        None => log::info!("No config file to watch for changes"),
+    }
+
     log::info!("Starting Server Instance");
     match server.run().await {
         Ok(_) => log::info!("Server closed"),
@@ -41,3 +315,181 @@ async fn main() {
         }
     }
 }
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            },
+            Err(e) => {
+                log::warn!("Failed to listen for SIGTERM due to {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+async fn devices(layers: &crossconfig::Layers, command: DevicesCommand) -> anyhow::Result<()> {
+    let socket = layers.load::<MasterConfig>()?.control_socket_path();
+
+    match command {
+        DevicesCommand::List { json } => {
+            let devices = match control::request(&socket, &ControlRequest::ListDevices).await? {
+                ControlReply::Devices { devices } => devices,
+                reply => return unexpected(reply),
+            };
+
+            if json {
+                println!("{}", serde_json::to_string_pretty(&devices)?);
+                return Ok(());
+            }
+
+            println!(
+                "{:<36}  {:<21}  {:<16}  {:<12}  {:<12}  CONNECTED",
+                "ID", "ADDRESS", "NAME", "GROUP", "USER"
+            );
+            for device in devices {
+                println!(
+                    "{:<36}  {:<21}  {:<16}  {:<12}  {:<12}  {}s",
+                    device.id,
+                    device.addr,
+                    device.name.unwrap_or_default(),
+                    device.group.unwrap_or_default(),
+                    device.user.unwrap_or_default(),
+                    device.connected_secs
+                );
+            }
+        }
+        DevicesCommand::Kick { id } => {
+            let req = ControlRequest::Kick { id: id.clone() };
+            match control::request(&socket, &req).await? {
+                ControlReply::Kicked { found: true } => println!("Kicked {}", id),
+                ControlReply::Kicked { found: false } => anyhow::bail!("No device {}", id),
+                reply => return unexpected(reply),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn unexpected(reply: ControlReply) -> anyhow::Result<()> {
+    match reply {
+        ControlReply::Error { message } => anyhow::bail!(message),
+        reply => anyhow::bail!("Unexpected reply {:?}", reply),
+    }
+}
+
+fn print_config(layers: &crossconfig::Layers) -> anyhow::Result<()> {
+    let config: MasterConfig = layers.load()?;
+    print!("{}", crossconfig::to_redacted_toml(&config)?);
+    Ok(())
+}
+
+fn check_config(layers: &crossconfig::Layers) -> anyhow::Result<()> {
+    let config: MasterConfig = layers.load()?;
+    let problems = config.check();
+
+    println!("Devices connect to {}", config.master_addr());
+    println!("Control socket {}", config.control_socket_path().display());
+    if let Some(accounts) = &config.accounts {
+        println!("Accounts stored in {}", accounts.database);
+    }
+    if let Some(history) = &config.history {
+        println!(
+            "History of {} entries per group stored in {}",
+            history.max_entries,
+            history.database.as_deref().unwrap_or("memory")
+        );
+    }
+    println!(
+        "Policy with {} rules, default {:?}",
+        config.policy.rules.len(),
+        config.policy.default
+    );
+    if let Some(metrics) = &config.metrics {
+        println!("Metrics served on {}", metrics.addr());
+    }
+    if let Some(admin) = &config.admin {
+        println!("Admin API served on {}", admin.addr());
+    }
+    if let Some(cluster) = &config.cluster {
+        println!("Cluster node '{}' on {}", cluster.name, cluster.listen);
+    }
+    if let Some(federation) = &config.federation {
+        for peer in &federation.peers {
+            println!(
+                "Federated with '{}' at {} for groups {:?}",
+                peer.name,
+                peer.addr.as_deref().unwrap_or("its own connection"),
+                peer.groups
+            );
+        }
+    }
+
+    if problems.is_empty() {
+        println!("Config OK");
+        return Ok(());
+    }
+
+    for problem in &problems {
+        eprintln!("Problem: {}", problem);
+    }
+    anyhow::bail!("Found {} problems in the config", problems.len())
+}
+
+fn gen_config(output: Option<String>, force: bool) -> anyhow::Result<()> {
+    let Some(path) = output else {
+        print!("{}", master_config::TEMPLATE);
+        return Ok(());
+    };
+
+    if !force && std::path::Path::new(&path).exists() {
+        anyhow::bail!("{} already exists, pass --force to overwrite it", path);
+    }
+    std::fs::write(&path, master_config::TEMPLATE)?;
+    println!("Wrote config template to {}", path);
+    Ok(())
+}
+
+fn open_accounts(
+    config: &master_config::AccountsConfig,
+) -> anyhow::Result<master_lib::users::UserStore> {
+    log::info!("Opening accounts database {}", config.database);
+    let store = master_lib::users::UserStore::open(&config.database)?;
+
+    if let (Some(user), Some(password)) = (&config.admin_user, &config.admin_password) {
+        if store.user(user)?.is_none() {
+            log::info!("Creating admin user '{}'", user);
+            store.add_user(user, password, true)?;
+        }
+    }
+
+    Ok(store)
+}
+
+/// Periodically reports the rate limit counters, as long as limits were hit.
+async fn log_rate_limits(layer: std::sync::Arc<master_lib::ratelimit::RateLimitLayer>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        let totals = layer.totals();
+        if totals.rate_limited > 0 || totals.too_large > 0 {
+            log::info!(
+                "Rate limits: {} allowed, {} rate limited, {} too large, {} disconnects",
+                totals.allowed,
+                totals.rate_limited,
+                totals.too_large,
+                totals.disconnects
+            );
+        }
+    }
+}
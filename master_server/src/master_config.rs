@@ -1,10 +1,205 @@
-use config::{File, FileFormat};
+use cross_messages::transport::TransportKind;
+use crossconfig::Layers;
+use master_lib::cluster::ClusterConfig;
+use master_lib::federation::FederationConfig;
+use master_lib::history::HistoryConfig;
+use master_lib::policy::Policy;
+use master_lib::ratelimit::RateLimitConfig;
 use serde::{Deserialize, Serialize};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+
+/// Name of the config file in the user config directory.
+pub const FILE_NAME: &str = "master_config.toml";
+
+/// Written by `master_server gen-config`, loads as the default config.
+pub const TEMPLATE: &str = r#"# Address devices connect to
+host_ip = "0.0.0.0"
+host_port = 7000
+
+# How devices connect, "tcp", "websocket" for networks only letting HTTP through
+# or "quic" (UDP) for devices switching networks
+# transport = "tcp"
+
+# One of error, warn, info, debug or trace, `LOGLEVEL` takes precedence
+# log_level = "info"
+
+# Seconds a pairing code stays valid
+pairing_ttl_secs = 300
+
+# Key of this Master, created on first start with a certificate next to it
+# (".crt"). Devices pin the fingerprint of the certificate when pairing.
+# Over QUIC they refuse any other certificate, over other transports they
+# only compare the fingerprint the Master reports, which proves nothing
+# identity_file = "/etc/crosslive/master.key"
+
+# Unix socket for `master_server devices ...`
+# control_socket = "/run/crosslive/master.sock"
+
+# Require accounts instead of letting any device register. Only the first
+# device of a user is added by logging in, later ones have to be paired
+# [accounts]
+# database = "/var/lib/crosslive/accounts.db"
+# admin_user = "admin"
+# admin_password = "change me"
+
+# Keep the latest clipboards of each group, for devices to browse later.
+# In memory only if no database is set
+# [history]
+# database = "/var/lib/crosslive/history.db"
+# max_entries = 100
+# max_age_secs = 604800
+# max_entry_bytes = 1048576
+
+# Which device may send which kind of message to whom, first match wins.
+# Rules with from or to match device names and need [accounts].
+[policy]
+default = "allow"
+# [[policy.rules]]
+# kinds = ["Clipboard"]
+# from = "work-laptop"
+# action = "deny"
+
+# [rate_limit]
+# messages_per_sec = 10.0
+# burst = 20
+# max_body_bytes = 1048576
+# max_violations = 10
+
+# [metrics]
+# host_ip = "127.0.0.1"
+# host_port = 9100
+
+# [admin]
+# host_ip = "127.0.0.1"
+# host_port = 9101
+# token = "change me"
+
+# [shutdown]
+# deadline_secs = 10
+# reconnect_after_secs = 30
+
+# Share devices of some groups with other Masters
+# [federation]
+# name = "office"
+# listen = "0.0.0.0:7001"
+# [[federation.peers]]
+# name = "home"
+# addr = "home.example.com:7001"
+# token = "change me"
+# groups = ["default"]
+
+# Or run as one node of several Masters sharing their devices,
+# the nodes are listed the same on every node. Only the devices are shared,
+# pairing codes, policy and history stay on each node, and accounts can't
+# be used as their revocations wouldn't reach the other nodes
+# [cluster]
+# name = "node-a"
+# listen = "0.0.0.0:7001"
+# token = "change me"
+# [[cluster.nodes]]
+# name = "node-a"
+# addr = "10.0.0.1:7001"
+# [[cluster.nodes]]
+# name = "node-b"
+# addr = "10.0.0.2:7001"
+"#;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MasterConfig {
+    #[serde(default = "default_host_ip")]
     pub host_ip: String,
+    #[serde(default = "default_host_port")]
     pub host_port: u16,
+    #[serde(default)]
+    pub transport: TransportKind,
+    pub log_level: Option<String>,
+    pub accounts: Option<AccountsConfig>,
+    pub history: Option<HistoryConfig>,
+    #[serde(default)]
+    pub policy: Policy,
+    #[serde(default = "default_pairing_ttl")]
+    pub pairing_ttl_secs: u64,
+    pub identity_file: Option<String>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub admin: Option<AdminConfig>,
+    pub control_socket: Option<String>,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    pub federation: Option<FederationConfig>,
+    pub cluster: Option<ClusterConfig>,
+}
+
+/// How the Master shuts down on Ctrl-C or SIGTERM.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ShutdownConfig {
+    /// Seconds given to in-flight relays and connections to finish.
+    #[serde(default = "default_shutdown_deadline")]
+    pub deadline_secs: u64,
+    /// Told to devices, so they know when to try reconnecting.
+    pub reconnect_after_secs: Option<u64>,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            deadline_secs: default_shutdown_deadline(),
+            reconnect_after_secs: None,
+        }
+    }
+}
+
+fn default_shutdown_deadline() -> u64 {
+    10
+}
+
+/// Enables the Prometheus endpoint at `http://<host_ip>:<host_port>/metrics`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct MetricsConfig {
+    pub host_ip: String,
+    pub host_port: u16,
+}
+
+/// Enables the admin API, bound separately from devices and guarded by `token`.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct AdminConfig {
+    pub host_ip: String,
+    pub host_port: u16,
+    pub token: String,
+}
+
+impl AdminConfig {
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host_ip, self.host_port)
+    }
+}
+
+impl MetricsConfig {
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.host_ip, self.host_port)
+    }
+}
+
+fn default_host_ip() -> String {
+    "0.0.0.0".to_string()
+}
+
+fn default_host_port() -> u16 {
+    7000
+}
+
+fn default_pairing_ttl() -> u64 {
+    300
+}
+
+/// Enables user accounts, stored in the sqlite database at `database`.
+/// The `admin_user` is created on startup if it doesn't exist yet.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct AccountsConfig {
+    pub database: String,
+    pub admin_user: Option<String>,
+    pub admin_password: Option<String>,
 }
 
 impl MasterConfig {
@@ -12,30 +207,218 @@ impl MasterConfig {
         format!("{}:{}", self.host_ip, self.host_port)
     }
 
-    pub fn get() -> Result<Self, config::ConfigError> {
-        if let Ok(v) = std::env::var("CROSSCONFIG") {
-            return Self::get_from(&v);
+    /// Path of the key the certificate of this Master is signed with.
+    pub fn identity_path(&self) -> Option<PathBuf> {
+        match &self.identity_file {
+            Some(path) => Some(PathBuf::from(path)),
+            None => dirs::config_local_dir().map(|dir| dir.join("crosslive_master.key")),
+        }
+    }
+
+    pub fn control_socket_path(&self) -> PathBuf {
+        match &self.control_socket {
+            Some(path) => PathBuf::from(path),
+            None => dirs::runtime_dir()
+                .unwrap_or_else(std::env::temp_dir)
+                .join("crosslive_master.sock"),
         }
+    }
 
-        let path = format!(
-            "{}/master_config.toml",
-            dirs::config_local_dir()
-                .unwrap()
-                .as_path()
-                .to_str()
-                .unwrap()
-        );
+    /// The links to other Masters, of either the federation or the cluster.
+    pub fn federation(&self) -> Option<FederationConfig> {
+        self.federation
+            .clone()
+            .or_else(|| self.cluster.as_ref().map(ClusterConfig::federation))
+    }
 
-        log::info!("No env path found, using default path {}", path);
+    /// Problems which would keep the Master from starting as configured.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let mut addrs = vec![("host", self.master_addr())];
+        if let Some(metrics) = &self.metrics {
+            addrs.push(("metrics", metrics.addr()));
+        }
+        if let Some(admin) = &self.admin {
+            addrs.push(("admin", admin.addr()));
+            if admin.token.is_empty() {
+                problems.push("admin.token is empty".to_string());
+            }
+        }
+
+        if let Some(federation) = &self.federation {
+            if let Some(listen) = &federation.listen {
+                addrs.push(("federation", listen.clone()));
+            }
+            problems.extend(check_federation(federation));
+        }
+        if let Some(cluster) = &self.cluster {
+            addrs.push(("cluster", cluster.listen.clone()));
+            problems.extend(cluster.check());
+            if self.federation.is_some() {
+                problems.push("federation and cluster can't both be set".to_string());
+            }
+            if self.accounts.is_some() {
+                problems.push(
+                    "accounts and cluster can't both be set, accounts are per node".to_string(),
+                );
+            }
+        }
+
+        let mut bound: Vec<(&str, SocketAddr)> = Vec::new();
+        for (name, addr) in &addrs {
+            let resolved = match addr.to_socket_addrs() {
+                Ok(resolved) => resolved,
+                Err(e) => {
+                    problems.push(format!("{} address {} is invalid: {}", name, addr, e));
+                    continue;
+                }
+            };
+
+            for addr in resolved {
+                let clash = bound.iter().find(|(_, other)| {
+                    other.port() == addr.port()
+                        && (other.ip() == addr.ip()
+                            || other.ip().is_unspecified()
+                            || addr.ip().is_unspecified())
+                });
+                if let Some((other, _)) = clash {
+                    problems.push(format!(
+                        "{} and {} both use port {}",
+                        other,
+                        name,
+                        addr.port()
+                    ));
+                    break;
+                }
+                bound.push((name, addr));
+            }
+        }
+
+        if let Some(accounts) = &self.accounts {
+            if accounts.admin_user.is_some() != accounts.admin_password.is_some() {
+                problems.push(
+                    "accounts.admin_user and accounts.admin_password must be set together"
+                        .to_string(),
+                );
+            }
+        }
+
+        // Without accounts a device registers under any name it likes
+        if self.accounts.is_none() && self.policy.rules.iter().any(|rule| rule.names_devices()) {
+            problems.push(
+                "policy rules with from or to need accounts, device names are not verified without them"
+                    .to_string(),
+            );
+        }
+
+        if let Some(history) = &self.history {
+            if history.max_entries == 0 || history.max_entry_bytes == 0 {
+                problems.push(
+                    "history.max_entries and history.max_entry_bytes must be greater than 0"
+                        .to_string(),
+                );
+            }
+        }
+
+        if let Some(level) = &self.log_level {
+            if crosslogging::parse_level(level).is_none() {
+                problems.push(format!("log_level '{}' is invalid", level));
+            }
+        }
+
+        if self.pairing_ttl_secs == 0 {
+            problems.push("pairing_ttl_secs must be greater than 0".to_string());
+        }
+
+        if let Some(rate_limit) = &self.rate_limit {
+            if rate_limit.messages_per_sec <= 0.0 || rate_limit.burst == 0 {
+                problems.push(
+                    "rate_limit.messages_per_sec and rate_limit.burst must be greater than 0"
+                        .to_string(),
+                );
+            }
+        }
 
-        Self::get_from(&path)
+        problems
     }
 
-    pub fn get_from(path: &str) -> Result<Self, config::ConfigError> {
-        log::info!("Attempting to load config from path: {}", path);
-        config::Config::builder()
-            .add_source(File::new(path, FileFormat::Toml))
-            .build()?
-            .try_deserialize()
+    /// Settings which differ from `other` and only apply after a restart.
+    pub fn restart_required(&self, other: &MasterConfig) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.master_addr() != other.master_addr() {
+            changed.push("host_ip/host_port");
+        }
+        if self.transport != other.transport {
+            changed.push("transport");
+        }
+        if self.accounts != other.accounts {
+            changed.push("accounts");
+        }
+        if self.history != other.history {
+            changed.push("history");
+        }
+        if self.pairing_ttl_secs != other.pairing_ttl_secs {
+            changed.push("pairing_ttl_secs");
+        }
+        if self.identity_file != other.identity_file {
+            changed.push("identity_file");
+        }
+        if self.metrics != other.metrics {
+            changed.push("metrics");
+        }
+        if self.admin != other.admin {
+            changed.push("admin");
+        }
+        if self.control_socket != other.control_socket {
+            changed.push("control_socket");
+        }
+        if self.shutdown != other.shutdown {
+            changed.push("shutdown");
+        }
+        if self.federation != other.federation {
+            changed.push("federation");
+        }
+        if self.cluster != other.cluster {
+            changed.push("cluster");
+        }
+        changed
     }
+
+    /// Loads the merged config and fails on any problem found by `check`.
+    pub fn load(layers: &Layers) -> anyhow::Result<Self> {
+        let config: MasterConfig = layers.load()?;
+        crossconfig::validate(config.check())?;
+        Ok(config)
+    }
+}
+
+fn check_federation(federation: &FederationConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    if federation.name.trim().is_empty() {
+        problems.push("federation.name is empty".to_string());
+    }
+
+    for (i, peer) in federation.peers.iter().enumerate() {
+        if peer.name == federation.name {
+            problems.push(format!("federation peer '{}' is this Master", peer.name));
+        }
+        if federation.peers[..i]
+            .iter()
+            .any(|other| other.name == peer.name)
+        {
+            problems.push(format!("federation peer '{}' is listed twice", peer.name));
+        }
+        if peer.token.is_empty() {
+            problems.push(format!(
+                "federation peer '{}' has an empty token",
+                peer.name
+            ));
+        }
+        if peer.groups.is_empty() {
+            problems.push(format!("federation peer '{}' shares no groups", peer.name));
+        }
+    }
+
+    problems
 }
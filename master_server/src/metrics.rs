@@ -0,0 +1,20 @@
+use master_lib::metrics::Metrics;
+use std::sync::Arc;
+
+/// Serves the Prometheus metrics of the Master at `GET /metrics`.
+pub async fn serve(addr: String, metrics: Arc<Metrics>) -> anyhow::Result<()> {
+    let app = axum::Router::new().route(
+        "/metrics",
+        axum::routing::get(move || async move {
+            (
+                [("content-type", "text/plain; version=0.0.4")],
+                metrics.render(),
+            )
+        }),
+    );
+
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    log::info!("Serving metrics on http://{}/metrics", addr);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use crate::master_config::MasterConfig;
+use crossconfig::{ConfigWatcher, Layers};
+use master_lib::control::MasterHandle;
+use master_lib::ratelimit::RateLimitLayer;
+use std::sync::Arc;
+
+/// Applies changes of the config file to the running Master where possible,
+/// and reports the ones which need a restart.
+pub struct Reloader {
+    layers: Layers,
+    /// The config the Master is running with.
+    config: MasterConfig,
+    handle: MasterHandle,
+    rate_limit: Arc<RateLimitLayer>,
+}
+
+impl Reloader {
+    pub fn new(
+        layers: Layers,
+        config: MasterConfig,
+        handle: MasterHandle,
+        rate_limit: Arc<RateLimitLayer>,
+    ) -> Self {
+        Reloader {
+            layers,
+            config,
+            handle,
+            rate_limit,
+        }
+    }
+
+    pub async fn run(mut self, mut watcher: ConfigWatcher) {
+        while watcher.changed().await {
+            log::info!("Reloading config from {}", watcher.path().display());
+
+            // Checking resolves the addresses, which blocks on DNS
+            let layers = self.layers.clone();
+            let res = tokio::task::spawn_blocking(move || MasterConfig::load(&layers)).await;
+            match res.map_err(anyhow::Error::from).and_then(|res| res) {
+                Ok(config) => self.apply(config).await,
+                Err(e) => log::error!("Keeping the current config, {}", e),
+            }
+        }
+    }
+
+    async fn apply(&mut self, config: MasterConfig) {
+        if config.log_level != self.config.log_level {
+            match crosslogging::set_config_level(config.log_level.as_deref()) {
+                Ok(()) => self.config.log_level = config.log_level.clone(),
+                Err(e) => log::error!("Failed to apply log level due to {}", e),
+            }
+        }
+
+        if config.rate_limit != self.config.rate_limit {
+            match &config.rate_limit {
+                Some(rate_limit) => {
+                    log::info!("Limiting connections to {:?}", rate_limit);
+                    self.rate_limit.set_config(rate_limit.clone());
+                    self.rate_limit.set_enabled(true);
+                }
+                None => {
+                    log::info!("Removed rate limits");
+                    self.rate_limit.set_enabled(false);
+                }
+            }
+            self.config.rate_limit = config.rate_limit.clone();
+        }
+
+        if config.policy != self.config.policy {
+            log::info!(
+                "Applying policy with {} rules, default {:?}",
+                config.policy.rules.len(),
+                config.policy.default
+            );
+            self.handle.set_policy(config.policy.clone()).await;
+            self.config.policy = config.policy.clone();
+        }
+
+        let restart = self.config.restart_required(&config);
+        if !restart.is_empty() {
+            log::warn!(
+                "Changes to {} only apply after a restart",
+                restart.join(", ")
+            );
+        }
+    }
+}